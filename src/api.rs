@@ -1,8 +1,13 @@
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use chrono::{DateTime, Utc};
 use failure::{Error, Fail};
 use lazy_static::lazy_static;
-use reqwest::{header, header::HeaderName, Body, Client};
+use reqwest::{header, header::HeaderName, Body, Client, Response, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sha2::{Digest, Sha256};
@@ -54,12 +59,18 @@ pub fn authorize(cli: &Client, token: &str) -> Result<AuthorizeResponse, reqwest
         .map_err(Into::into)
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Path(pub String);
 
-#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ContentHash(String);
 
+impl std::fmt::Display for ContentHash {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        self.0.fmt(formatter)
+    }
+}
+
 #[derive(Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 pub struct FileEntry {
     pub name: String,
@@ -67,6 +78,14 @@ pub struct FileEntry {
     pub path_display: String,
     pub size: usize,
     pub content_hash: Option<ContentHash>,
+    pub client_modified: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FolderEntry {
+    pub name: String,
+    pub id: Path,
+    pub path_display: String,
 }
 
 #[derive(Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
@@ -75,7 +94,7 @@ pub enum Entry {
     #[serde(rename = "file")]
     File(FileEntry),
     #[serde(rename = "folder")]
-    Folder,
+    Folder(FolderEntry),
 }
 
 #[derive(Debug, Deserialize)]
@@ -88,14 +107,32 @@ pub struct ListFolderResponse {
     pub has_more: bool,
 }
 
+/// lists `path`, which is "" for the root. the rest is listed by `list_folder_continue` if `has_more`.
 pub fn list_folder(
     cli: &Client,
     access_token: &AccessToken,
+    path: &str,
+    recursive: bool,
 ) -> Result<ListFolderResponse, reqwest::Error> {
     cli.post(url::LIST_FOLDER)
         .bearer_auth(access_token)
         .header(header::CONTENT_TYPE, "application/json")
-        .json(&json!({"path": ""}))
+        .json(&json!({"path": path, "recursive": recursive}))
+        .send()?
+        .error_for_status()?
+        .json()
+        .map_err(Into::into)
+}
+
+pub fn list_folder_continue(
+    cli: &Client,
+    access_token: &AccessToken,
+    cursor: &Cursor,
+) -> Result<ListFolderResponse, reqwest::Error> {
+    cli.post(url::LIST_FOLDER_CONTINUE)
+        .bearer_auth(access_token)
+        .header(header::CONTENT_TYPE, "application/json")
+        .json(&json!({"cursor": cursor.0}))
         .send()?
         .error_for_status()?
         .json()
@@ -146,43 +183,115 @@ pub fn delete(cli: &Client, access_token: &AccessToken, path: &Path) -> Result<(
     Ok(())
 }
 
+#[derive(Fail, Debug)]
+#[fail(display = "range request is not satisfied: {}", _0)]
+struct RangeError(StatusCode);
+
+/// downloads `path` from `offset` into `dst`.
+/// `hasher` must have been fed the first `offset` bytes of the file,
+/// so that the returned hash covers the whole file.
 pub fn download<W: Write>(
     cli: &Client,
     access_token: &AccessToken,
     path: &Path,
+    offset: u64,
+    mut hasher: ContentHasher,
     dst: &mut W,
 ) -> Result<ContentHash, Error> {
-    let mut resp = cli
+    let mut req = cli
         .post(url::DOWNLOAD)
         .bearer_auth(access_token)
-        .header(&*DROPBOX_API_ARG, json!({ "path": path }).to_string())
-        .send()?
-        .error_for_status()?;
-    let mut buf = Vec::with_capacity(url::CONTENT_HASH_BLOCK_SIZE);
-    let mut hashes = Vec::new();
+        .header(&*DROPBOX_API_ARG, json!({ "path": path }).to_string());
+    if offset > 0 {
+        req = req.header(header::RANGE, format!("bytes={}-", offset));
+    }
+    let mut resp = req.send()?.error_for_status()?;
+    if offset > 0 && resp.status() != StatusCode::PARTIAL_CONTENT {
+        Err(RangeError(resp.status()))?;
+    }
+    let mut buf = vec![0; url::CONTENT_HASH_BLOCK_SIZE];
     loop {
-        let len = resp
-            .by_ref()
-            .take(url::CONTENT_HASH_BLOCK_SIZE as u64)
-            .read_to_end(&mut buf)?;
-        if len <= 0 {
+        let len = resp.read(&mut buf)?;
+        if len == 0 {
             break;
         }
+        hasher.update(&buf[..len]);
+        dst.write_all(&buf[..len])?;
+    }
+    Ok(hasher.finish())
+}
+
+/// dropbox content hash: sha256 of the concatenated sha256 of every 4 MiB block.
+pub struct ContentHasher {
+    block: Sha256,
+    block_len: usize,
+    hashes: Vec<u8>,
+}
+
+impl ContentHasher {
+    pub fn new() -> ContentHasher {
+        ContentHasher {
+            block: Sha256::new(),
+            block_len: 0,
+            hashes: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let len = (url::CONTENT_HASH_BLOCK_SIZE - self.block_len).min(data.len());
+            self.block.input(&data[..len]);
+            self.block_len += len;
+            data = &data[len..];
+            if self.block_len == url::CONTENT_HASH_BLOCK_SIZE {
+                self.end_block();
+            }
+        }
+    }
+
+    fn end_block(&mut self) {
+        let block = std::mem::replace(&mut self.block, Sha256::new());
+        self.hashes.extend(block.result().to_vec());
+        self.block_len = 0;
+    }
+
+    pub fn finish(mut self) -> ContentHash {
+        if self.block_len > 0 {
+            self.end_block();
+        }
         let mut hasher = Sha256::new();
-        hasher.input(&buf);
-        hashes.extend(hasher.result().to_vec());
-        dst.write(&buf)?;
+        hasher.input(&self.hashes);
+        ContentHash(hasher.result().to_vec().to_hex())
+    }
+}
 
-        buf.clear();
+impl Write for ContentHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
     }
 
-    let mut hasher = Sha256::new();
-    hasher.input(&hashes);
-    Ok(ContentHash(hasher.result().to_vec().to_hex()))
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct SessionId(String);
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SessionId(String);
+
+#[derive(Debug, Serialize, Clone, Copy)]
+enum UploadSessionType {
+    #[serde(rename = "sequential")]
+    Sequential,
+    #[serde(rename = "concurrent")]
+    Concurrent,
+}
+
+#[derive(Debug, Serialize)]
+struct UploadSessionStartConfig {
+    close: bool,
+    session_type: UploadSessionType,
+}
 
 #[derive(Debug, Deserialize)]
 struct UploadSessionStartResponse {
@@ -193,11 +302,15 @@ fn upload_session_start<T: Into<Body>>(
     cli: &Client,
     access_token: &AccessToken,
     body: T,
-    close: bool,
+    config: UploadSessionStartConfig,
 ) -> Result<UploadSessionStartResponse, reqwest::Error> {
     cli.post(url::UPLOAD_SESSION_START)
         .bearer_auth(access_token)
-        .header(&*DROPBOX_API_ARG, json!({ "close": close }).to_string())
+        .header(
+            &*DROPBOX_API_ARG,
+            serde_json::to_string(&config)
+                .expect("valid Dropbox-API-Arg header of upload_session/start"),
+        )
         .header(header::CONTENT_TYPE, "application/octet-stream")
         .body(body)
         .send()?
@@ -206,10 +319,10 @@ fn upload_session_start<T: Into<Body>>(
         .map_err(Into::into)
 }
 
-#[derive(Debug, Serialize)]
-struct UploadSessionCursor {
-    session_id: SessionId,
-    offset: usize,
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct UploadSessionCursor {
+    pub session_id: SessionId,
+    pub offset: usize,
 }
 
 #[derive(Debug, Serialize)]
@@ -218,13 +331,60 @@ struct UploadSessionConfig<'a> {
     close: bool,
 }
 
+#[derive(Debug, Deserialize)]
+struct ErrorResponse<E> {
+    error_summary: String,
+    error: E,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = ".tag")]
+enum UploadSessionLookupError {
+    #[serde(rename = "not_found")]
+    NotFound,
+    #[serde(rename = "incorrect_offset")]
+    IncorrectOffset { correct_offset: usize },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Fail, Debug)]
+pub enum UploadError {
+    #[fail(display = "upload session is not found or has expired")]
+    NotFound,
+    #[fail(display = "incorrect offset: dropbox has received {} bytes", _0)]
+    IncorrectOffset(usize),
+    #[fail(display = "upload failed: {}", _0)]
+    Api(String),
+    #[fail(
+        display = "content_hash mismatched in {}. expected: {:?}, actual: {:?}",
+        _0, _1, _2
+    )]
+    ContentHashMismatch(String, ContentHash, Option<ContentHash>),
+}
+
+fn check_lookup(mut resp: Response) -> Result<Response, Error> {
+    if resp.status() == StatusCode::CONFLICT {
+        let err: ErrorResponse<UploadSessionLookupError> = resp.json()?;
+        Err(match err.error {
+            UploadSessionLookupError::NotFound => UploadError::NotFound,
+            UploadSessionLookupError::IncorrectOffset { correct_offset } => {
+                UploadError::IncorrectOffset(correct_offset)
+            }
+            UploadSessionLookupError::Other => UploadError::Api(err.error_summary),
+        })?;
+    }
+    Ok(resp.error_for_status()?)
+}
+
 fn upload_session_append<T: Into<Body>>(
     cli: &Client,
     access_token: &AccessToken,
     body: T,
     config: UploadSessionConfig,
-) -> Result<(), reqwest::Error> {
-    cli.post(url::UPLOAD_SESSION_APPEND)
+) -> Result<(), Error> {
+    let resp = cli
+        .post(url::UPLOAD_SESSION_APPEND)
         .bearer_auth(access_token)
         .header(
             &*DROPBOX_API_ARG,
@@ -233,8 +393,8 @@ fn upload_session_append<T: Into<Body>>(
         )
         .header(header::CONTENT_TYPE, "application/octet-stream")
         .body(body)
-        .send()?
-        .error_for_status()?;
+        .send()?;
+    check_lookup(resp)?;
     Ok(())
 }
 
@@ -249,6 +409,8 @@ struct UploadSessionFinishCommit<'a> {
     path: &'a str,
     mode: UploadSessionFinishMode,
     autorename: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_modified: Option<String>,
     mute: bool,
     strict_conflict: bool,
 }
@@ -264,7 +426,7 @@ fn upload_session_finish<T: Into<Body>>(
     access_token: &AccessToken,
     body: T,
     config: UploadSessionFinishConfig,
-) -> Result<(), reqwest::Error> {
+) -> Result<FileEntry, reqwest::Error> {
     cli.post(url::UPLOAD_SESSION_FINISH)
         .bearer_auth(access_token)
         .header(header::CONTENT_TYPE, "application/octet-stream")
@@ -275,64 +437,732 @@ fn upload_session_finish<T: Into<Body>>(
         )
         .body(body)
         .send()?
-        .error_for_status()?;
-    Ok(())
+        .error_for_status()?
+        .json()
+        .map_err(Into::into)
 }
 
-pub fn upload<R: Read>(
+fn files_upload<T: Into<Body>>(
     cli: &Client,
     access_token: &AccessToken,
-    mut body: R,
-    path: &str,
-    chunk_size: usize,
-) -> Result<(), Error> {
-    let mut buf = vec![0; chunk_size];
-    let len = body.read(&mut buf)?;
-    buf.resize(len, 0);
-    let session_id = upload_session_start(cli, access_token, buf, false)?.session_id;
+    body: T,
+    commit: UploadSessionFinishCommit,
+) -> Result<FileEntry, reqwest::Error> {
+    cli.post(url::UPLOAD)
+        .bearer_auth(access_token)
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(
+            &*DROPBOX_API_ARG,
+            serde_json::to_string(&commit).expect("valid Dropbox-API-Arg header of upload"),
+        )
+        .body(body)
+        .send()?
+        .error_for_status()?
+        .json()
+        .map_err(Into::into)
+}
 
-    let mut cursor = UploadSessionCursor {
-        session_id: session_id,
-        offset: len,
-    };
+// dropbox takes client_modified in whole seconds.
+fn commit(path: &str, client_modified: Option<DateTime<Utc>>) -> UploadSessionFinishCommit<'_> {
+    UploadSessionFinishCommit {
+        path: path,
+        mode: UploadSessionFinishMode::Add,
+        autorename: true,
+        client_modified: client_modified.map(|t| t.format("%Y-%m-%dT%H:%M:%SZ").to_string()),
+        mute: true,
+        strict_conflict: true,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UploadOptions {
+    pub chunk_size: usize,
+    /// resize chunks of a sequential session by the observed throughput and retries.
+    pub adaptive: bool,
+    pub streams: usize,
+    pub retries: usize,
+    pub retry_wait: Duration,
+    /// the modification time of the source, which dropbox keeps as client_modified.
+    pub client_modified: Option<DateTime<Utc>>,
+}
+
+pub fn is_not_found(e: &Error) -> bool {
+    match e.downcast_ref() {
+        Some(UploadError::NotFound) => true,
+        _ => false,
+    }
+}
+
+pub fn is_hash_mismatch(e: &Error) -> bool {
+    match e.downcast_ref() {
+        Some(UploadError::ContentHashMismatch(..)) => true,
+        _ => false,
+    }
+}
+
+/// persists the progress of a sequential upload so that it can be continued later.
+pub trait Checkpoint {
+    fn resume(&self) -> Option<UploadSessionCursor>;
+    fn save(&mut self, cursor: &UploadSessionCursor);
+}
 
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(64);
+
+fn retry<T, F: FnMut() -> Result<T, Error>>(options: &UploadOptions, mut f: F) -> Result<T, Error> {
+    let mut wait = options.retry_wait;
+    let mut attempt = 0;
     loop {
-        let mut buf = vec![0; chunk_size];
-        let len = body.read(&mut buf)?;
-        if len == 0 {
-            break;
+        match f() {
+            Ok(r) => return Ok(r),
+            Err(e) => {
+                if attempt >= options.retries || is_not_found(&e) {
+                    return Err(e);
+                }
+                log::warn!("{}\nretry in {:?}", e, wait);
+                thread::sleep(wait);
+                wait = (wait * 2).min(MAX_RETRY_WAIT);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// reads a shared chunk from `pos` as a request body,
+/// so that a retry sends the chunk again without copying it.
+struct ChunkRead {
+    chunk: Arc<Vec<u8>>,
+    pos: usize,
+}
+
+impl ChunkRead {
+    fn new(chunk: &Arc<Vec<u8>>, pos: usize) -> ChunkRead {
+        ChunkRead {
+            chunk: chunk.clone(),
+            pos: pos,
+        }
+    }
+}
+
+impl Read for ChunkRead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = (&self.chunk[self.pos..]).read(buf)?;
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl From<ChunkRead> for Body {
+    fn from(r: ChunkRead) -> Body {
+        let len = (r.chunk.len() - r.pos) as u64;
+        Body::sized(r, len)
+    }
+}
+
+// takes the buffer of `chunk` back for the next chunk
+// unless a request which has given up still holds it.
+fn recycle(chunk: Arc<Vec<u8>>, chunk_size: usize) -> Vec<u8> {
+    Arc::try_unwrap(chunk).unwrap_or_else(|_| Vec::with_capacity(chunk_size))
+}
+
+pub const MIN_CHUNK_SIZE: usize = url::CONTENT_HASH_BLOCK_SIZE;
+pub const MAX_CHUNK_SIZE: usize = 32 * MIN_CHUNK_SIZE;
+// an adaptive chunk is resized so that sending it takes about this long
+const CHUNK_TARGET_TIME: Duration = Duration::from_secs(10);
+
+struct ChunkSizer {
+    size: usize,
+    adaptive: bool,
+}
+
+impl ChunkSizer {
+    fn new(options: &UploadOptions) -> ChunkSizer {
+        ChunkSizer {
+            size: options.chunk_size,
+            adaptive: options.adaptive,
         }
-        buf.resize(len, 0);
-        upload_session_append(
+    }
+
+    /// doubles the chunk size when a chunk was sent quickly,
+    /// and halves it when a chunk was slow or had to be retried.
+    fn record(&mut self, elapsed: Duration, retried: usize) {
+        if !self.adaptive {
+            return;
+        }
+        let size = if retried > 0 || elapsed > CHUNK_TARGET_TIME * 2 {
+            self.size / 2
+        } else if elapsed < CHUNK_TARGET_TIME / 2 {
+            self.size * 2
+        } else {
+            self.size
+        };
+        let size = (size / MIN_CHUNK_SIZE * MIN_CHUNK_SIZE)
+            .max(MIN_CHUNK_SIZE)
+            .min(MAX_CHUNK_SIZE);
+        if size != self.size {
+            log::debug!("change chunk size to {} bytes", size);
+            self.size = size;
+        }
+    }
+}
+
+/// appends `chunk` at `offset`, retrying on failure.
+/// with `resync`, an incorrect_offset error inside the chunk skips the bytes dropbox already has,
+/// and one beyond the chunk is returned at once for the caller to skip the body.
+/// returns how many times the chunk was retried.
+fn append_chunk(
+    cli: &Client,
+    access_token: &AccessToken,
+    cursor: &UploadSessionCursor,
+    chunk: &Arc<Vec<u8>>,
+    close: bool,
+    resync: bool,
+    options: &UploadOptions,
+) -> Result<usize, Error> {
+    let offset = cursor.offset;
+    let mut sent = 0;
+    let mut attempts = 0;
+    let mut ahead = None;
+    retry(options, || {
+        attempts += 1;
+        if ahead.is_some() || (sent == chunk.len() && !close) {
+            return Ok(());
+        }
+        let cursor = UploadSessionCursor {
+            session_id: cursor.session_id.clone(),
+            offset: offset + sent,
+        };
+        let result = upload_session_append(
             cli,
             access_token,
-            buf,
+            ChunkRead::new(chunk, sent),
             UploadSessionConfig {
                 cursor: &cursor,
-                close: false,
+                close: close,
             },
-        )?;
-        cursor.offset += len;
+        );
+        if let Err(ref e) = result {
+            if let Some(UploadError::IncorrectOffset(correct)) = e.downcast_ref() {
+                if resync && offset <= *correct && *correct <= offset + chunk.len() {
+                    log::warn!("resync upload session from offset {}", correct);
+                    sent = correct - offset;
+                } else if resync && *correct > offset + chunk.len() {
+                    ahead = Some(*correct);
+                    return Ok(());
+                }
+            }
+        }
+        result
+    })?;
+    if let Some(correct) = ahead {
+        Err(UploadError::IncorrectOffset(correct))?;
     }
+    Ok(attempts - 1)
+}
+
+struct HashRead<R> {
+    inner: R,
+    hasher: ContentHasher,
+}
+
+impl<R> HashRead<R> {
+    fn new(inner: R) -> Self {
+        HashRead {
+            inner: inner,
+            hasher: ContentHasher::new(),
+        }
+    }
+}
+
+impl<R: Read> Read for HashRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+}
 
-    upload_session_finish(
+// a committed file whose content differs from what was sent is deleted,
+// so that the server never delivers it.
+fn verify(
+    cli: &Client,
+    access_token: &AccessToken,
+    entry: FileEntry,
+    hash: ContentHash,
+) -> Result<FileEntry, Error> {
+    if entry.content_hash.as_ref() != Some(&hash) {
+        if let Err(e) = delete(cli, access_token, &entry.id) {
+            log::warn!("cannot delete {} from Dropbox: {}", entry.path_display, e);
+        }
+        return Err(
+            UploadError::ContentHashMismatch(entry.path_display, hash, entry.content_hash).into(),
+        );
+    }
+    Ok(entry)
+}
+
+/// uploads `body` to `path` and returns the metadata of the committed file,
+/// whose name may differ from `path` by autorename.
+/// `size` is the length of `body` if it is known in advance.
+/// a body which fits in one chunk is sent by a single request.
+///
+/// when `checkpoint` has a cursor, the bytes before its offset are read only for the content hash.
+pub fn upload<R: Read>(
+    cli: &Client,
+    access_token: &AccessToken,
+    body: R,
+    path: &str,
+    options: &UploadOptions,
+    size: Option<u64>,
+    checkpoint: Option<&mut dyn Checkpoint>,
+) -> Result<FileEntry, Error> {
+    let mut body = HashRead::new(body);
+    let entry = upload_body(
         cli,
         access_token,
-        vec![],
-        UploadSessionFinishConfig {
-            cursor: &cursor,
-            commit: UploadSessionFinishCommit {
-                path: path,
-                mode: UploadSessionFinishMode::Add,
-                autorename: true,
-                mute: true,
-                strict_conflict: true,
-            },
-        },
+        &mut body,
+        path,
+        options,
+        size,
+        checkpoint,
     )?;
+    verify(cli, access_token, entry, body.hasher.finish())
+}
+
+fn upload_body<R: Read>(
+    cli: &Client,
+    access_token: &AccessToken,
+    mut body: R,
+    path: &str,
+    options: &UploadOptions,
+    size: Option<u64>,
+    checkpoint: Option<&mut dyn Checkpoint>,
+) -> Result<FileEntry, Error> {
+    let resumed = checkpoint.as_ref().and_then(|c| c.resume());
+    if let Some(resumed) = resumed {
+        io::copy(
+            &mut body.by_ref().take(resumed.offset as u64),
+            &mut io::sink(),
+        )?;
+        let cursor = send_sequential(
+            cli,
+            access_token,
+            Arc::new(vec![]),
+            body,
+            options,
+            checkpoint,
+            false,
+        )?;
+        return finish(cli, access_token, &cursor, path, options);
+    }
+
+    let mut first = Vec::with_capacity(options.chunk_size);
+    read_chunk(&mut body, &mut first, options.chunk_size)?;
+    if first.len() < options.chunk_size || size == Some(first.len() as u64) {
+        let first = Arc::new(first);
+        return retry(options, || {
+            Ok(files_upload(
+                cli,
+                access_token,
+                ChunkRead::new(&first, 0),
+                commit(path, options.client_modified),
+            )?)
+        });
+    }
+    let first = Arc::new(first);
+
+    let cursor = if options.streams > 1 {
+        send_concurrent(cli, access_token, first, body, options)?
+    } else {
+        send_sequential(cli, access_token, first, body, options, checkpoint, false)?
+    };
+    finish(cli, access_token, &cursor, path, options)
+}
+
+/// a closed upload session waiting for `finish_batch`.
+#[derive(Debug, Clone)]
+pub struct StagedUpload {
+    pub cursor: UploadSessionCursor,
+    pub path: String,
+    pub hash: ContentHash,
+    pub client_modified: Option<DateTime<Utc>>,
+}
+
+/// uploads `body` into a closed upload session without committing it,
+/// so that it can be committed later by `finish_batch`.
+pub fn upload_session<R: Read>(
+    cli: &Client,
+    access_token: &AccessToken,
+    body: R,
+    path: &str,
+    options: &UploadOptions,
+) -> Result<StagedUpload, Error> {
+    let mut body = HashRead::new(body);
+    let mut first = Vec::with_capacity(options.chunk_size);
+    read_chunk(&mut body, &mut first, options.chunk_size)?;
+    let first = Arc::new(first);
+    let cursor = if options.streams > 1 {
+        send_concurrent(cli, access_token, first, &mut body, options)?
+    } else {
+        send_sequential(cli, access_token, first, &mut body, options, None, true)?
+    };
+    Ok(StagedUpload {
+        cursor: cursor,
+        path: path.to_owned(),
+        hash: body.hasher.finish(),
+        client_modified: options.client_modified,
+    })
+}
+
+fn finish(
+    cli: &Client,
+    access_token: &AccessToken,
+    cursor: &UploadSessionCursor,
+    path: &str,
+    options: &UploadOptions,
+) -> Result<FileEntry, Error> {
+    retry(options, || {
+        Ok(upload_session_finish(
+            cli,
+            access_token,
+            vec![],
+            UploadSessionFinishConfig {
+                cursor: cursor,
+                commit: commit(path, options.client_modified),
+            },
+        )?)
+    })
+}
+
+// `first` is sent with upload_session/start.
+// when `checkpoint` has a cursor instead, `body` must be positioned at its offset.
+fn send_sequential<R: Read>(
+    cli: &Client,
+    access_token: &AccessToken,
+    first: Arc<Vec<u8>>,
+    mut body: R,
+    options: &UploadOptions,
+    mut checkpoint: Option<&mut dyn Checkpoint>,
+    close: bool,
+) -> Result<UploadSessionCursor, Error> {
+    let resumed = checkpoint.as_ref().and_then(|c| c.resume());
+    let mut cursor = match resumed {
+        Some(cursor) => cursor,
+        None => {
+            let len = first.len();
+            let session_id = retry(options, || {
+                Ok(upload_session_start(
+                    cli,
+                    access_token,
+                    ChunkRead::new(&first, 0),
+                    UploadSessionStartConfig {
+                        close: false,
+                        session_type: UploadSessionType::Sequential,
+                    },
+                )?)
+            })?
+            .session_id;
+
+            UploadSessionCursor {
+                session_id: session_id,
+                offset: len,
+            }
+        }
+    };
+    if let Some(ref mut checkpoint) = checkpoint {
+        checkpoint.save(&cursor);
+    }
+
+    let mut sizer = ChunkSizer::new(options);
+    let mut buf = recycle(first, options.chunk_size);
+    loop {
+        read_chunk(&mut body, &mut buf, sizer.size)?;
+        if buf.is_empty() {
+            break;
+        }
+        let chunk = Arc::new(buf);
+        let start = Instant::now();
+        let result = append_chunk(cli, access_token, &cursor, &chunk, false, true, options);
+        let end = cursor.offset + chunk.len();
+        match result {
+            Ok(retried) => {
+                sizer.record(start.elapsed(), retried);
+                cursor.offset = end;
+            }
+            // dropbox received a chunk after the checkpoint was saved for the last time,
+            // possibly a larger one than this, so the body is skipped to where it is.
+            Err(e) => {
+                let ahead = match e.downcast_ref() {
+                    Some(&UploadError::IncorrectOffset(correct)) if correct > end => correct,
+                    _ => return Err(e),
+                };
+                log::warn!("resync upload session from offset {}", ahead);
+                io::copy(
+                    &mut body.by_ref().take((ahead - end) as u64),
+                    &mut io::sink(),
+                )?;
+                cursor.offset = ahead;
+            }
+        }
+        if let Some(ref mut checkpoint) = checkpoint {
+            checkpoint.save(&cursor);
+        }
+        buf = recycle(chunk, options.chunk_size);
+    }
+
+    if close {
+        append_chunk(
+            cli,
+            access_token,
+            &cursor,
+            &Arc::new(buf),
+            true,
+            true,
+            options,
+        )?;
+    }
+    Ok(cursor)
+}
+
+// fills `buf` up to `chunk_size` so that a short read, like from a pipe,
+// never makes a short chunk in the middle of the body.
+fn read_chunk<R: Read>(body: &mut R, buf: &mut Vec<u8>, chunk_size: usize) -> io::Result<()> {
+    buf.clear();
+    body.by_ref().take(chunk_size as u64).read_to_end(buf)?;
     Ok(())
 }
 
+// every chunk but the last must be a multiple of 4 MiB in a concurrent session,
+// so chunks are filled completely. at most `2 * streams + 2` chunks are held in memory:
+// `streams` in flight, `streams` queued, the one being sent last and the one read ahead.
+fn send_concurrent<R: Read>(
+    cli: &Client,
+    access_token: &AccessToken,
+    first: Arc<Vec<u8>>,
+    mut body: R,
+    options: &UploadOptions,
+) -> Result<UploadSessionCursor, Error> {
+    let session_id = retry(options, || {
+        Ok(upload_session_start(
+            cli,
+            access_token,
+            vec![],
+            UploadSessionStartConfig {
+                close: false,
+                session_type: UploadSessionType::Concurrent,
+            },
+        )?)
+    })?
+    .session_id;
+
+    let (send, recv) = mpsc::sync_channel::<(usize, Arc<Vec<u8>>)>(options.streams);
+    let recv = Arc::new(Mutex::new(recv));
+    let (give_back, spare) = mpsc::channel();
+    let failed = Arc::new(AtomicBool::new(false));
+    let workers: Vec<_> = (0..options.streams)
+        .map(|_| {
+            let cli = cli.clone();
+            let access_token = access_token.clone();
+            let session_id = session_id.clone();
+            let options = options.clone();
+            let recv = recv.clone();
+            let failed = failed.clone();
+            let give_back = give_back.clone();
+            thread::spawn(move || -> Result<(), Error> {
+                loop {
+                    let next = recv.lock().expect("lock chunk queue").recv();
+                    let (offset, chunk) = match next {
+                        Ok(next) => next,
+                        Err(_) => return Ok(()),
+                    };
+                    let cursor = UploadSessionCursor {
+                        session_id: session_id.clone(),
+                        offset: offset,
+                    };
+                    let result = append_chunk(
+                        &cli,
+                        &access_token,
+                        &cursor,
+                        &chunk,
+                        false,
+                        false,
+                        &options,
+                    );
+                    if let Err(e) = result {
+                        failed.store(true, Ordering::SeqCst);
+                        return Err(e);
+                    }
+                    if let Ok(buf) = Arc::try_unwrap(chunk) {
+                        let _ = give_back.send(buf);
+                    }
+                }
+            })
+        })
+        .collect();
+    // the channel is disconnected once every worker has failed,
+    // so that sending to it does not block forever.
+    drop(recv);
+
+    let mut offset = 0;
+    let mut chunk = first;
+    while !failed.load(Ordering::SeqCst) {
+        let mut next = spare
+            .try_recv()
+            .unwrap_or_else(|_| Vec::with_capacity(options.chunk_size));
+        read_chunk(&mut body, &mut next, options.chunk_size)?;
+        if next.is_empty() {
+            break;
+        }
+        let len = chunk.len();
+        if let Err(mpsc::SendError((_, unsent))) = send.send((offset, chunk)) {
+            chunk = unsent;
+            break;
+        }
+        offset += len;
+        chunk = Arc::new(next);
+    }
+    drop(send);
+
+    for worker in workers {
+        worker.join().expect("join upload worker")?;
+    }
+
+    let mut cursor = UploadSessionCursor {
+        session_id: session_id,
+        offset: offset,
+    };
+    append_chunk(cli, access_token, &cursor, &chunk, true, false, options)?;
+    cursor.offset += chunk.len();
+    Ok(cursor)
+}
+
+pub const FINISH_BATCH_LIMIT: usize = 1000;
+
+#[derive(Debug, Serialize)]
+struct UploadSessionFinishBatchConfig<'a> {
+    entries: Vec<UploadSessionFinishConfig<'a>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = ".tag")]
+enum UploadSessionFinishBatchResultEntry {
+    #[serde(rename = "success")]
+    Success(FileEntry),
+    #[serde(rename = "failure")]
+    Failure { failure: serde_json::Value },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = ".tag")]
+enum UploadSessionFinishBatchJobStatus {
+    #[serde(rename = "async_job_id")]
+    AsyncJobId { async_job_id: String },
+    #[serde(rename = "in_progress")]
+    InProgress,
+    #[serde(rename = "complete")]
+    Complete {
+        entries: Vec<UploadSessionFinishBatchResultEntry>,
+    },
+    #[serde(rename = "failed")]
+    Failed,
+    #[serde(other)]
+    Other,
+}
+
+fn upload_session_finish_batch(
+    cli: &Client,
+    access_token: &AccessToken,
+    config: &UploadSessionFinishBatchConfig,
+) -> Result<UploadSessionFinishBatchJobStatus, reqwest::Error> {
+    cli.post(url::UPLOAD_SESSION_FINISH_BATCH)
+        .bearer_auth(access_token)
+        .header(header::CONTENT_TYPE, "application/json")
+        .json(config)
+        .send()?
+        .error_for_status()?
+        .json()
+        .map_err(Into::into)
+}
+
+fn upload_session_finish_batch_check(
+    cli: &Client,
+    access_token: &AccessToken,
+    async_job_id: &str,
+) -> Result<UploadSessionFinishBatchJobStatus, reqwest::Error> {
+    cli.post(url::UPLOAD_SESSION_FINISH_BATCH_CHECK)
+        .bearer_auth(access_token)
+        .header(header::CONTENT_TYPE, "application/json")
+        .json(&json!({ "async_job_id": async_job_id }))
+        .send()?
+        .error_for_status()?
+        .json()
+        .map_err(Into::into)
+}
+
+const FINISH_BATCH_POLL_WAIT: Duration = Duration::from_secs(1);
+
+/// commits closed upload sessions to their paths at once.
+/// at most `FINISH_BATCH_LIMIT` entries can be committed by one call.
+pub fn finish_batch(
+    cli: &Client,
+    access_token: &AccessToken,
+    entries: &[StagedUpload],
+    options: &UploadOptions,
+) -> Result<Vec<Result<FileEntry, Error>>, Error> {
+    let config = UploadSessionFinishBatchConfig {
+        entries: entries
+            .iter()
+            .map(|staged| UploadSessionFinishConfig {
+                cursor: &staged.cursor,
+                commit: commit(&staged.path, staged.client_modified),
+            })
+            .collect(),
+    };
+    let mut status = retry(options, || {
+        Ok(upload_session_finish_batch(cli, access_token, &config)?)
+    })?;
+    let async_job_id = match status {
+        UploadSessionFinishBatchJobStatus::AsyncJobId { ref async_job_id } => {
+            Some(async_job_id.clone())
+        }
+        _ => None,
+    };
+
+    loop {
+        match status {
+            UploadSessionFinishBatchJobStatus::Complete { entries: results } => {
+                return Ok(results
+                    .into_iter()
+                    .zip(entries)
+                    .map(|(result, staged)| match result {
+                        UploadSessionFinishBatchResultEntry::Success(entry) => {
+                            verify(cli, access_token, entry, staged.hash.clone())
+                        }
+                        UploadSessionFinishBatchResultEntry::Failure { failure } => {
+                            Err(UploadError::Api(failure.to_string()).into())
+                        }
+                    })
+                    .collect());
+            }
+            UploadSessionFinishBatchJobStatus::AsyncJobId { .. }
+            | UploadSessionFinishBatchJobStatus::InProgress => {
+                let async_job_id = async_job_id.as_ref().expect("async_job_id of finish_batch");
+                thread::sleep(FINISH_BATCH_POLL_WAIT);
+                status = retry(options, || {
+                    Ok(upload_session_finish_batch_check(
+                        cli,
+                        access_token,
+                        async_job_id,
+                    )?)
+                })?;
+            }
+            UploadSessionFinishBatchJobStatus::Failed
+            | UploadSessionFinishBatchJobStatus::Other => {
+                Err(UploadError::Api("finish_batch failed".to_string()))?
+            }
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Account {
     pub account_id: String,
@@ -349,3 +1179,50 @@ pub fn get_current_account(
         .json()
         .map_err(Into::into)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: usize = url::CONTENT_HASH_BLOCK_SIZE;
+
+    // the content hash computed from whole blocks at once
+    fn reference(data: &[u8]) -> ContentHash {
+        let mut hashes = vec![];
+        for block in data.chunks(BLOCK) {
+            hashes.extend(Sha256::digest(block).to_vec());
+        }
+        ContentHash(Sha256::digest(&hashes).to_vec().to_hex())
+    }
+
+    #[test]
+    fn empty_content_hash() {
+        let hash = ContentHasher::new().finish();
+        assert_eq!(
+            hash,
+            ContentHash(
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_owned()
+            )
+        );
+        assert_eq!(hash, reference(&[]));
+    }
+
+    #[test]
+    fn odd_writes_across_blocks() {
+        let writes = [1, 7, 4093, 65_537, 1_000_003];
+        for &len in &[0, BLOCK - 1, BLOCK, BLOCK + 1, 2 * BLOCK + 3] {
+            let data: Vec<u8> = (0..len).map(|i| (i * 31 % 251) as u8).collect();
+            let mut hasher = ContentHasher::new();
+            let mut rest = &data[..];
+            for &n in writes.iter().cycle() {
+                if rest.is_empty() {
+                    break;
+                }
+                let (head, tail) = rest.split_at(n.min(rest.len()));
+                hasher.write_all(head).unwrap();
+                rest = tail;
+            }
+            assert_eq!(hasher.finish(), reference(&data), "{} bytes", len);
+        }
+    }
+}
@@ -0,0 +1,435 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+
+use failure::{Error, Fail};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use tar::EntryType;
+
+use crate::walk::{self, WalkOptions};
+
+const PIPE_BUFFER: usize = 64 * 1024;
+const PIPE_DEPTH: usize = 16;
+
+#[derive(Fail, Debug)]
+#[fail(display = "unsafe path in archive: {:?}", _0)]
+struct UnsafePath(PathBuf);
+
+#[derive(Fail, Debug)]
+#[fail(display = "{:?} in archive is not a regular file or directory", _0)]
+struct UnsupportedEntry(PathBuf);
+
+#[derive(Fail, Debug)]
+#[fail(display = "archive expands to more than {} bytes", _0)]
+struct TooLarge(u64);
+
+#[derive(Fail, Debug)]
+#[fail(display = "archive has more than {} entries", _0)]
+struct TooManyEntries(usize);
+
+struct PipeWrite {
+    send: SyncSender<io::Result<Vec<u8>>>,
+}
+
+impl Write for PipeWrite {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send
+            .send(Ok(buf.to_vec()))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "archive is not read"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// an archive which is written by another thread while it is read.
+/// an error in writing is returned from `read` instead of the end of the archive.
+pub struct ArchiveRead {
+    recv: Receiver<io::Result<Vec<u8>>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ArchiveRead {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            match self.recv.recv() {
+                Ok(buf) => {
+                    self.buf = buf?;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let len = (&self.buf[self.pos..]).read(out)?;
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+fn write_tar<W: Write>(w: W, files: &[(PathBuf, PathBuf)]) -> io::Result<W> {
+    let mut builder = tar::Builder::new(w);
+    for (path, rel) in files {
+        builder.append_path_with_name(path, rel)?;
+    }
+    builder.into_inner()
+}
+
+/// name of the archive of `dir` in Dropbox.
+pub fn tar_name(dir: &Path, gzip: bool) -> Result<String, Error> {
+    let ext = if gzip { "tar.gz" } else { "tar" };
+    Ok(format!("/{}.{}", walk::dir_name(dir)?, ext))
+}
+
+/// streams a tar archive of the files under `dir`, with paths relative to `dir`.
+pub fn tar(dir: &Path, options: &WalkOptions, gzip: bool) -> Result<ArchiveRead, Error> {
+    let files = walk::relative_files(dir, options)?;
+    let (send, recv) = mpsc::sync_channel(PIPE_DEPTH);
+    let error = send.clone();
+    thread::spawn(move || {
+        let pipe = BufWriter::with_capacity(PIPE_BUFFER, PipeWrite { send: send });
+        let result = if gzip {
+            write_tar(GzEncoder::new(pipe, Compression::default()), &files)
+                .and_then(|gz| gz.finish())
+                .and_then(|mut pipe| pipe.flush())
+        } else {
+            write_tar(pipe, &files).and_then(|mut pipe| pipe.flush())
+        };
+        if let Err(e) = result {
+            let _ = error.send(Err(e));
+        }
+    });
+    Ok(ArchiveRead {
+        recv: recv,
+        buf: Vec::new(),
+        pos: 0,
+    })
+}
+
+/// caps on what is unpacked from one archive, against archive bombs.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_size: u64,
+    pub max_entries: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+// the name of the directory which `path` is unpacked into, and the format of the archive.
+fn archive_stem(path: &Path) -> Option<(&str, Format)> {
+    let name = path.file_name()?.to_str()?;
+    [
+        (".tar", Format::Tar),
+        (".tar.gz", Format::TarGz),
+        (".tgz", Format::TarGz),
+        (".zip", Format::Zip),
+    ]
+    .iter()
+    .find(|(ext, _)| name.len() > ext.len() && name.ends_with(ext))
+    .map(|&(ext, format)| (&name[..name.len() - ext.len()], format))
+}
+
+pub fn is_archive(path: &Path) -> bool {
+    archive_stem(path).is_some()
+}
+
+// creates a directory in `parent` which did not exist, so nothing is overwritten by unpacking.
+fn create_new_dir(parent: &Path, name: &str) -> Result<PathBuf, Error> {
+    let mut i = 0;
+    loop {
+        let path = parent.join(if i == 0 {
+            name.to_owned()
+        } else {
+            format!("{} ({})", name, i)
+        });
+        match fs::create_dir(&path) {
+            Ok(_) => return Ok(path),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e.into()),
+        }
+        i += 1;
+    }
+}
+
+// counts entries and unpacked bytes of an archive against `Limits`.
+struct Budget {
+    limits: Limits,
+    size: u64,
+    entries: usize,
+}
+
+impl Budget {
+    fn new(limits: Limits) -> Budget {
+        Budget {
+            limits: limits,
+            size: 0,
+            entries: 0,
+        }
+    }
+
+    fn entry(&mut self) -> Result<(), Error> {
+        self.entries += 1;
+        if self.entries > self.limits.max_entries {
+            Err(TooManyEntries(self.limits.max_entries))?;
+        }
+        Ok(())
+    }
+
+    fn reserve(&mut self, size: u64) -> Result<(), Error> {
+        self.size = self.size.saturating_add(size);
+        if self.size > self.limits.max_size {
+            Err(TooLarge(self.limits.max_size))?;
+        }
+        Ok(())
+    }
+
+    fn remaining(&self) -> u64 {
+        self.limits.max_size - self.size
+    }
+}
+
+fn check_path(path: &Path) -> Result<(), Error> {
+    let safe = path.components().all(|c| match c {
+        Component::Normal(_) | Component::CurDir => true,
+        _ => false,
+    });
+    if !safe {
+        Err(UnsafePath(path.to_owned()))?;
+    }
+    Ok(())
+}
+
+fn unpack_tar<R: Read>(r: R, dst: &Path, budget: &mut Budget) -> Result<(), Error> {
+    let mut archive = tar::Archive::new(r);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        budget.entry()?;
+        let path = entry.path()?.into_owned();
+        check_path(&path)?;
+        let entry_type = entry.header().entry_type();
+        if entry_type != EntryType::Regular && entry_type != EntryType::Directory {
+            Err(UnsupportedEntry(path))?;
+        }
+        // the reader of an entry never returns more than the size in its header
+        budget.reserve(entry.header().size()?)?;
+        entry.unpack_in(dst)?;
+    }
+    Ok(())
+}
+
+const S_IFMT: u32 = 0o170_000;
+const S_IFLNK: u32 = 0o120_000;
+
+fn unpack_zip<R: Read + Seek>(r: R, dst: &Path, budget: &mut Budget) -> Result<(), Error> {
+    let mut archive = zip::ZipArchive::new(r)?;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        budget.entry()?;
+        let path = PathBuf::from(file.name());
+        check_path(&path)?;
+        if file.unix_mode().map_or(false, |m| m & S_IFMT == S_IFLNK) {
+            return Err(UnsupportedEntry(path).into());
+        }
+        let out = dst.join(&path);
+        if file.is_dir() {
+            fs::create_dir_all(&out)?;
+            continue;
+        }
+        if let Some(parent) = out.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut w = BufWriter::new(
+            fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&out)?,
+        );
+        // the size in a zip entry is not trusted, so the decompressed bytes are counted
+        let remaining = budget.remaining();
+        let size = io::copy(&mut (&mut file).take(remaining + 1), &mut w)?;
+        budget.reserve(size)?;
+        w.flush()?;
+    }
+    Ok(())
+}
+
+/// unpacks `archive` into a new directory next to it, named after the archive.
+/// the archive is refused if it has an absolute path, `..`, a symlink or anything other than
+/// regular files and directories, or if it goes over `limits`.
+pub fn extract(archive: &Path, limits: Limits) -> Result<PathBuf, Error> {
+    let (stem, format) = archive_stem(archive).expect("extract an archive");
+    let parent = archive.parent().unwrap_or_else(|| Path::new("."));
+    let dst = create_new_dir(parent, stem)?;
+    let r = BufReader::new(File::open(archive)?);
+    let mut budget = Budget::new(limits);
+    let result = match format {
+        Format::Tar => unpack_tar(r, &dst, &mut budget),
+        Format::TarGz => unpack_tar(GzDecoder::new(r), &dst, &mut budget),
+        Format::Zip => unpack_zip(r, &dst, &mut budget),
+    };
+    if let Err(e) = result {
+        if let Err(e) = fs::remove_dir_all(&dst) {
+            log::warn!("cannot remove {}: {}", dst.display(), e);
+        }
+        return Err(e);
+    }
+    Ok(dst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    const LIMITS: Limits = Limits {
+        max_size: 1024,
+        max_entries: 8,
+    };
+
+    // an empty directory of the test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("ptfs-archive-{}-{}", name, process::id()));
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // `tar::Builder` refuses unsafe paths, so the name is written into the header directly.
+    fn raw_header(name: &str, size: u64, entry_type: EntryType) -> tar::Header {
+        let mut header = tar::Header::new_old();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_entry_type(entry_type);
+        header.set_cksum();
+        header
+    }
+
+    fn write_tar(path: &Path, entries: &[(tar::Header, &[u8])]) {
+        let mut builder = tar::Builder::new(File::create(path).unwrap());
+        for (header, data) in entries {
+            builder.append(header, *data).unwrap();
+        }
+        builder.finish().unwrap();
+    }
+
+    fn write_zip(path: &Path, entries: &[(&str, &[u8])]) {
+        let mut w = zip::ZipWriter::new(File::create(path).unwrap());
+        for (name, data) in entries {
+            w.start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            w.write_all(data).unwrap();
+        }
+        w.finish().unwrap();
+    }
+
+    // the archive is refused and nothing but archives is left in `dir`
+    fn assert_refused<F: Fail>(dir: &Path, archive: &Path) {
+        let e = extract(archive, LIMITS).unwrap_err();
+        assert!(e.downcast_ref::<F>().is_some(), "unexpected error: {}", e);
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            assert!(path.is_file() && is_archive(&path), "{:?} is left", path);
+        }
+    }
+
+    #[test]
+    fn extract_tar_and_zip() {
+        let dir = test_dir("ok");
+        let tar = dir.join("a.tar");
+        write_tar(
+            &tar,
+            &[
+                (raw_header("d", 0, EntryType::Directory), b""),
+                (raw_header("d/x", 3, EntryType::Regular), b"abc"),
+            ],
+        );
+        let zip = dir.join("a.zip");
+        write_zip(&zip, &[("d/x", b"abc")]);
+
+        assert_eq!(extract(&tar, LIMITS).unwrap(), dir.join("a"));
+        assert_eq!(fs::read(dir.join("a/d/x")).unwrap(), b"abc");
+        assert_eq!(extract(&zip, LIMITS).unwrap(), dir.join("a (1)"));
+        assert_eq!(fs::read(dir.join("a (1)/d/x")).unwrap(), b"abc");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuse_parent_dir() {
+        let dir = test_dir("parent");
+        let tar = dir.join("a.tar");
+        write_tar(
+            &tar,
+            &[(raw_header("../evil", 3, EntryType::Regular), b"abc")],
+        );
+        assert_refused::<UnsafePath>(&dir, &tar);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuse_absolute_path() {
+        let dir = test_dir("absolute");
+        let evil = dir.join("evil");
+        let zip = dir.join("a.zip");
+        write_zip(&zip, &[(evil.to_str().unwrap(), b"abc")]);
+        assert_refused::<UnsafePath>(&dir, &zip);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuse_symlink() {
+        let dir = test_dir("symlink");
+        let tar = dir.join("a.tar");
+        let mut link = raw_header("link", 0, EntryType::Symlink);
+        link.set_link_name("/etc/passwd").unwrap();
+        link.set_cksum();
+        write_tar(&tar, &[(link, b"")]);
+        assert_refused::<UnsupportedEntry>(&dir, &tar);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuse_too_large() {
+        let dir = test_dir("large");
+        let data = vec![0; LIMITS.max_size as usize + 1];
+        let tar = dir.join("a.tar");
+        write_tar(
+            &tar,
+            &[(
+                raw_header("x", data.len() as u64, EntryType::Regular),
+                &data,
+            )],
+        );
+        assert_refused::<TooLarge>(&dir, &tar);
+        // the size in a zip entry is not trusted
+        let zip = dir.join("b.zip");
+        write_zip(&zip, &[("x", &data)]);
+        assert_refused::<TooLarge>(&dir, &zip);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuse_too_many_entries() {
+        let dir = test_dir("entries");
+        let names: Vec<_> = (0..=LIMITS.max_entries).map(|i| i.to_string()).collect();
+        let entries: Vec<_> = names.iter().map(|n| (n.as_str(), &b"x"[..])).collect();
+        let zip = dir.join("a.zip");
+        write_zip(&zip, &entries);
+        assert_refused::<TooManyEntries>(&dir, &zip);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -3,7 +3,7 @@ use std::io::{self, BufReader, BufWriter, Write};
 use std::path::PathBuf;
 
 use aes_ctr::stream_cipher::generic_array::typenum::uint::Unsigned;
-use aes_ctr::stream_cipher::{NewStreamCipher, SyncStreamCipher};
+use aes_ctr::stream_cipher::{NewStreamCipher, SyncStreamCipher, SyncStreamCipherSeek};
 
 use rustc_hex::FromHex;
 use failure::{Error, Fail};
@@ -69,6 +69,7 @@ impl Config {
     }
 }
 
+#[derive(Clone)]
 pub struct CipherGen {
     key: Vec<u8>,
     nonce: Vec<u8>,
@@ -92,7 +93,7 @@ impl CipherGen {
         })
     }
 
-    pub fn cipher(&self) -> impl SyncStreamCipher {
+    pub fn cipher(&self) -> impl SyncStreamCipher + SyncStreamCipherSeek {
         aes_ctr::Aes256Ctr::new_var(&self.key, &self.nonce).expect("valid size key and nonce")
     }
 }
@@ -1,6 +1,6 @@
-use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 
-use aes_ctr::stream_cipher::SyncStreamCipher;
+use aes_ctr::stream_cipher::{SyncStreamCipher, SyncStreamCipherSeek};
 use failure::Error;
 use reqwest::Client;
 use sha2::{Digest, Sha256};
@@ -55,6 +55,23 @@ fn apply(config: Config) -> Result<(), Error> {
     Ok(())
 }
 
+/// a keystream which can be started from any byte offset of the plaintext.
+/// a chunked format would seek to the chunk which contains `pos`.
+pub trait KeystreamSeek {
+    fn keystream_pos(&self) -> u64;
+    fn seek_keystream(&mut self, pos: u64);
+}
+
+impl<C: SyncStreamCipherSeek> KeystreamSeek for C {
+    fn keystream_pos(&self) -> u64 {
+        self.current_pos()
+    }
+
+    fn seek_keystream(&mut self, pos: u64) {
+        self.seek(pos);
+    }
+}
+
 pub struct CipherRead<C, R> {
     cipher: C,
     inner: R,
@@ -70,13 +87,21 @@ impl<C, R> CipherRead<C, R> {
 }
 
 impl<C: SyncStreamCipher, R: Read> Read for CipherRead<C, R> {
-    fn read(&mut self, mut bytes: &mut [u8]) -> io::Result<usize> {
+    fn read(&mut self, bytes: &mut [u8]) -> io::Result<usize> {
         let len = self.inner.read(bytes)?;
-        self.cipher.apply_keystream(&mut bytes);
+        self.cipher.apply_keystream(&mut bytes[..len]);
         Ok(len)
     }
 }
 
+impl<C: KeystreamSeek, R: Seek> Seek for CipherRead<C, R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = self.inner.seek(pos)?;
+        self.cipher.seek_keystream(pos);
+        Ok(pos)
+    }
+}
+
 pub struct CipherWrite<C, W> {
     cipher: C,
     inner: W,
@@ -91,14 +116,179 @@ impl<C, W> CipherWrite<C, W> {
     }
 }
 
-impl<C: SyncStreamCipher, W: Write> Write for CipherWrite<C, W> {
+// the keystream is rewound to the end of the bytes which `inner` actually took,
+// so that the rest is encrypted with the same keystream when it is written again.
+impl<C: SyncStreamCipher + KeystreamSeek, W: Write> Write for CipherWrite<C, W> {
     fn flush(&mut self) -> io::Result<()> {
         self.inner.flush()
     }
 
     fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        let pos = self.cipher.keystream_pos();
         let mut v = src.to_vec();
         self.cipher.apply_keystream(&mut v);
-        self.inner.write(&v)
+        let result = self.inner.write(&v);
+        let written = *result.as_ref().unwrap_or(&0);
+        if written < v.len() {
+            self.cipher.seek_keystream(pos + written as u64);
+        }
+        result
+    }
+}
+
+impl<C: KeystreamSeek, W: Seek> Seek for CipherWrite<C, W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = self.inner.seek(pos)?;
+        self.cipher.seek_keystream(pos);
+        Ok(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes_ctr::stream_cipher::NewStreamCipher;
+    use aes_ctr::Aes256Ctr;
+    use std::io::Cursor;
+
+    fn cipher() -> Aes256Ctr {
+        Aes256Ctr::new_var(&[7; 32], &[9; 16]).expect("valid size key and nonce")
+    }
+
+    fn encrypt(plain: &[u8]) -> Vec<u8> {
+        let mut v = plain.to_vec();
+        cipher().apply_keystream(&mut v);
+        v
+    }
+
+    // a linear congruential generator, good enough to pick sizes and split points
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self) -> u64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            self.0 >> 33
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            self.next() as usize % n
+        }
+
+        fn bytes(&mut self, len: usize) -> Vec<u8> {
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+    }
+
+    // returns at most `max` bytes for each call
+    struct ShortRead<R> {
+        inner: R,
+        max: usize,
+    }
+
+    impl<R: Read> Read for ShortRead<R> {
+        fn read(&mut self, bytes: &mut [u8]) -> io::Result<usize> {
+            let len = bytes.len().min(self.max);
+            self.inner.read(&mut bytes[..len])
+        }
+    }
+
+    // takes at most `max` bytes for each call
+    struct ShortWrite {
+        buf: Vec<u8>,
+        max: usize,
+    }
+
+    impl Write for ShortWrite {
+        fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+            let len = src.len().min(self.max);
+            self.buf.extend_from_slice(&src[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn short_reads_follow_keystream() {
+        let mut rng = Lcg(1);
+        for _ in 0..50 {
+            let len = rng.below(2048);
+            let plain = rng.bytes(len);
+            let inner = ShortRead {
+                inner: &plain[..],
+                max: 1 + rng.below(64),
+            };
+            let mut r = CipherRead::new(cipher(), inner);
+            let mut read = vec![];
+            loop {
+                let mut buf = vec![0; 1 + rng.below(100)];
+                let n = r.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                read.extend_from_slice(&buf[..n]);
+            }
+            assert_eq!(read, encrypt(&plain));
+        }
+    }
+
+    #[test]
+    fn short_writes_follow_keystream() {
+        let mut rng = Lcg(2);
+        for _ in 0..50 {
+            let len = rng.below(2048);
+            let plain = rng.bytes(len);
+            let inner = ShortWrite {
+                buf: vec![],
+                max: 1 + rng.below(64),
+            };
+            let mut w = CipherWrite::new(cipher(), inner);
+            let mut rest = &plain[..];
+            while !rest.is_empty() {
+                let (head, tail) = rest.split_at(rng.below(rest.len()) + 1);
+                w.write_all(head).unwrap();
+                rest = tail;
+            }
+            assert_eq!(w.inner.buf, encrypt(&plain));
+        }
+    }
+
+    #[test]
+    fn seek_read() {
+        let mut rng = Lcg(3);
+        let plain = rng.bytes(1000);
+        let mut r = CipherRead::new(cipher(), Cursor::new(encrypt(&plain)));
+        for _ in 0..20 {
+            let pos = rng.below(plain.len() + 1);
+            let mut read = vec![];
+            assert_eq!(r.seek(SeekFrom::Start(pos as u64)).unwrap(), pos as u64);
+            r.read_to_end(&mut read).unwrap();
+            assert_eq!(read, &plain[pos..]);
+        }
+        let back = rng.below(plain.len()) as i64 + 1;
+        let mut read = vec![];
+        r.seek(SeekFrom::Current(-back)).unwrap();
+        r.read_to_end(&mut read).unwrap();
+        assert_eq!(read, &plain[plain.len() - back as usize..]);
+    }
+
+    #[test]
+    fn seek_write() {
+        let mut rng = Lcg(4);
+        for _ in 0..20 {
+            let plain = rng.bytes(1000);
+            let pos = rng.below(plain.len() + 1);
+            let mut w = CipherWrite::new(cipher(), Cursor::new(vec![]));
+            w.seek(SeekFrom::Start(pos as u64)).unwrap();
+            w.write_all(&plain[pos..]).unwrap();
+            w.seek(SeekFrom::Start(0)).unwrap();
+            w.write_all(&plain[..pos]).unwrap();
+            assert_eq!(w.inner.into_inner(), encrypt(&plain));
+        }
     }
 }
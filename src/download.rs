@@ -1,74 +1,421 @@
 use std::fs::{self, File};
 use std::io::{self, Read, BufReader};
 use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 use std::time::Duration;
 
+use chrono::DateTime;
 use failure::{Error, Fail};
-use indicatif::ProgressBar;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use reqwest::{Client, ClientBuilder};
 
 use crate::api;
+use crate::archive;
 use crate::config::{Config, CipherGen};
 use crate::crypto::CipherRead;
+use crate::resume::UploadState;
+use crate::route;
+use crate::walk::{self, Source, WalkOptions};
 
-const CHUNK: usize = 4 * 1024 * 1024;
+const MIB: usize = 1024 * 1024;
+const RETRY_WAIT: Duration = Duration::from_secs(1);
+// files are committed together by finish_batch when at least this many are given
+const BATCH_THRESHOLD: usize = 16;
 
 #[derive(Fail, Debug)]
-#[fail(display = "cannot get file name of {:?}", _0)]
-struct CreateNameError(PathBuf);
+#[fail(display = "cannot commit upload: {}", _0)]
+struct CommitError(String);
 
+#[derive(Fail, Debug)]
+#[fail(
+    display = "chunk size must be a multiple of {} MiB up to {} MiB: {}",
+    _0, _1, _2
+)]
+struct InvalidChunkSize(usize, usize, usize);
 
 fn download_read<B: Read>(
     cli: &Client,
     access_token: &api::AccessToken,
     gen: &Option<CipherGen>,
+    options: &api::UploadOptions,
     name: &str,
     body: B,
-) -> Result<(), Error> {
+    size: Option<u64>,
+    state: Option<&mut UploadState>,
+) -> Result<api::FileEntry, Error> {
+    let checkpoint = state.map(|s| s as &mut dyn api::Checkpoint);
     match gen {
         Some(gen) => api::upload(
-            &cli,
+            cli,
             access_token,
             CipherRead::new(gen.cipher(), body),
             name,
-            CHUNK,
-        )?,
-        None => api::upload(&cli, access_token, body, name, CHUNK)?,
-    };
-    Ok(())
+            options,
+            size,
+            checkpoint,
+        ),
+        None => api::upload(cli, access_token, body, name, options, size, checkpoint),
+    }
+}
+
+// `options` for a file modified at the time in `meta`
+fn with_mtime(options: &api::UploadOptions, meta: &fs::Metadata) -> api::UploadOptions {
+    api::UploadOptions {
+        client_modified: meta.modified().ok().map(DateTime::from),
+        ..options.clone()
+    }
 }
 
 fn download_file(
     cli: &Client,
     access_token: &api::AccessToken,
     gen: &Option<CipherGen>,
-    path: &PathBuf,
-    quiet: bool,
-) -> Result<(), Error> {
-    let pb = if quiet {
-        ProgressBar::hidden()
+    options: &api::UploadOptions,
+    source: &Source,
+    pb: &ProgressBar,
+) -> Result<api::FileEntry, Error> {
+    let (path, name) = (&source.path, &source.name);
+    let meta = fs::metadata(path)?;
+    let options = &with_mtime(options, &meta);
+
+    // concurrent sessions cannot be continued from an offset
+    let mut state = if options.streams > 1 {
+        None
     } else {
-        let meta = fs::metadata(path)?;
-        ProgressBar::new(meta.len())
+        Some(UploadState::load(path, name, &meta)?)
     };
+    if let Some(offset) = state.as_ref().map(|s| s.offset()).filter(|&o| o > 0) {
+        log::info!("resume uploading {} from {} bytes", path.display(), offset);
+    }
+    let mut attempt = 0;
+    loop {
+        let offset = state.as_ref().map(|s| s.offset()).unwrap_or(0);
+        let result = download_read(
+            cli,
+            access_token,
+            gen,
+            options,
+            &name,
+            BufReader::new(pb.wrap_read(File::open(path)?)),
+            Some(meta.len()),
+            state.as_mut(),
+        );
+        match result {
+            Err(ref e) if offset > 0 && api::is_not_found(e) => {
+                log::warn!("upload session of {} is lost, start over", path.display());
+            }
+            Err(ref e) if api::is_hash_mismatch(e) && attempt < options.retries => {
+                attempt += 1;
+                log::warn!("{}, upload {} again", e, path.display());
+            }
+            _ => {
+                if let (Ok(_), Some(state)) = (&result, &state) {
+                    state.remove();
+                }
+                return result;
+            }
+        }
+        if let Some(state) = state.as_mut() {
+            state.reset();
+        }
+        pb.set_position(0);
+    }
+}
+
+
+fn progress_bar() -> ProgressBar {
+    let pb = ProgressBar::new(0);
+    pb.set_style(
+        ProgressStyle::default_bar().template("{msg} {wide_bar} {bytes}/{total_bytes} ({eta})"),
+    );
+    pb
+}
+
+// shows the upload of `path` on a bar of `progress_bar`
+fn show_progress(pb: &ProgressBar, path: &PathBuf) {
+    let len = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    pb.set_length(len);
+    pb.set_position(0);
+    pb.set_message(&path.display().to_string());
+}
+
+// the size of an archive is not known while it is streamed
+fn spinner(path: &PathBuf) -> ProgressBar {
+    let pb = ProgressBar::new_spinner();
+    pb.set_style(ProgressStyle::default_spinner().template("{msg} {spinner} {bytes}"));
+    pb.set_message(&path.display().to_string());
+    pb
+}
 
-    let name = path
-        .file_name()
-        .and_then(|n| n.to_str())
-        .ok_or_else(|| CreateNameError(path.to_owned()))?;
-    let name = &format!("/{}", name);
-
-    download_read(
-        cli,
-        access_token,
-        gen,
-        &name,
-        BufReader::new(pb.wrap_read(File::open(path)?)),
-    )
+fn upload_files<T, F>(
+    sources: &[Source],
+    quiet: bool,
+    jobs: usize,
+    upload: F,
+) -> Vec<Result<T, Error>>
+where
+    T: Send + 'static,
+    F: Fn(&Source, &ProgressBar) -> Result<T, Error> + Send + Sync + 'static,
+{
+    let multi = MultiProgress::new();
+    if quiet {
+        multi.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    // one bar for the whole upload and one for each worker, which it reuses for every file
+    let total = multi.add(ProgressBar::new(sources.len() as u64));
+    total.set_style(ProgressStyle::default_bar().template("{msg} {wide_bar} {pos}/{len} files"));
+    total.set_message("total");
+    let queue: Vec<_> = sources.iter().cloned().enumerate().collect();
+    let queue = Arc::new(Mutex::new(queue.into_iter()));
+    let upload = Arc::new(upload);
+
+    let (send, recv) = mpsc::channel();
+    for _ in 0..jobs.max(1).min(sources.len()) {
+        let upload = upload.clone();
+        let queue = queue.clone();
+        let send = send.clone();
+        let pb = multi.add(progress_bar());
+        thread::spawn(move || loop {
+            let next = queue.lock().expect("lock upload queue").next();
+            let (i, source) = match next {
+                Some(next) => next,
+                None => return pb.finish_and_clear(),
+            };
+            show_progress(&pb, &source.path);
+            let result = upload(&source, &pb);
+            if let Err(e) = send.send((i, result)) {
+                log::error!("cannot send to channel: {}", e);
+            }
+        });
+    }
+    drop(send);
+
+    let draw = thread::spawn(move || multi.join());
+    let mut results = Vec::with_capacity(sources.len());
+    for result in recv.iter() {
+        total.inc(1);
+        results.push(result);
+    }
+    total.finish();
+    match draw.join() {
+        Ok(Err(e)) => log::warn!("cannot draw progress bars: {}", e),
+        Err(_) => log::warn!("cannot draw progress bars"),
+        Ok(Ok(_)) => {}
+    }
+
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
 }
 
+fn stage_file(
+    cli: &Client,
+    access_token: &api::AccessToken,
+    gen: &Option<CipherGen>,
+    options: &api::UploadOptions,
+    source: &Source,
+    pb: &ProgressBar,
+) -> Result<api::StagedUpload, Error> {
+    let name = &source.name;
+    let options = &with_mtime(options, &fs::metadata(&source.path)?);
+    let body = BufReader::new(pb.wrap_read(File::open(&source.path)?));
+    match gen {
+        Some(gen) => api::upload_session(
+            cli,
+            access_token,
+            CipherRead::new(gen.cipher(), body),
+            name,
+            options,
+        ),
+        None => api::upload_session(cli, access_token, body, name, options),
+    }
+}
+
+// the offset of the saved session of `source`, which only a sequential upload continues
+fn saved_offset(options: &api::UploadOptions, source: &Source) -> u64 {
+    if options.streams > 1 {
+        return 0;
+    }
+    match fs::metadata(&source.path) {
+        Ok(meta) => UploadState::load(&source.path, &source.name, &meta)
+            .map(|s| s.offset())
+            .unwrap_or(0),
+        Err(_) => 0,
+    }
+}
+
+fn download_each(
+    cli: &Client,
+    access_token: &api::AccessToken,
+    gen: &Option<CipherGen>,
+    options: &api::UploadOptions,
+    sources: &[Source],
+    quiet: bool,
+    jobs: usize,
+) -> Vec<Result<api::FileEntry, Error>> {
+    let (c, t, g, o) = (
+        cli.clone(),
+        access_token.clone(),
+        gen.clone(),
+        options.clone(),
+    );
+    upload_files(sources, quiet, jobs, move |source, pb| {
+        download_file(&c, &t, &g, &o, source, pb)
+    })
+}
+
+fn download_files(
+    cli: &Client,
+    access_token: &api::AccessToken,
+    gen: &Option<CipherGen>,
+    options: &api::UploadOptions,
+    sources: &[Source],
+    quiet: bool,
+    jobs: usize,
+) -> Vec<Result<api::FileEntry, Error>> {
+    // a batch never continues a saved session, so those files are uploaded one by one.
+    let (resumed, fresh): (Vec<_>, Vec<_>) = sources
+        .iter()
+        .cloned()
+        .enumerate()
+        .partition(|(_, source)| saved_offset(options, source) > 0);
+    if fresh.len() < BATCH_THRESHOLD {
+        return download_each(cli, access_token, gen, options, sources, quiet, jobs);
+    }
+
+    let mut results: Vec<Option<Result<api::FileEntry, Error>>> =
+        sources.iter().map(|_| None).collect();
+    let (indices, resumed): (Vec<_>, Vec<_>) = resumed.into_iter().unzip();
+    let uploaded = download_each(cli, access_token, gen, options, &resumed, quiet, jobs);
+    for (i, result) in indices.into_iter().zip(uploaded) {
+        results[i] = Some(result);
+    }
+    let (indices, fresh): (Vec<_>, Vec<_>) = fresh.into_iter().unzip();
+    let committed = batch_files(cli, access_token, gen, options, &fresh, quiet, jobs);
+    for (i, result) in indices.into_iter().zip(committed) {
+        results[i] = Some(result);
+    }
+    results
+        .into_iter()
+        .map(|result| result.expect("result of every file"))
+        .collect()
+}
+
+fn batch_files(
+    cli: &Client,
+    access_token: &api::AccessToken,
+    gen: &Option<CipherGen>,
+    options: &api::UploadOptions,
+    sources: &[Source],
+    quiet: bool,
+    jobs: usize,
+) -> Vec<Result<api::FileEntry, Error>> {
+    let (c, t, g, o) = (
+        cli.clone(),
+        access_token.clone(),
+        gen.clone(),
+        options.clone(),
+    );
+
+    // upload every file into a closed session first, then commit them by finish_batch
+    // to avoid lock contention of the namespace.
+    let staged = upload_files(sources, quiet, jobs, move |source, pb| {
+        stage_file(&c, &t, &g, &o, source, pb)
+    });
+    let mut results: Vec<Option<Result<api::FileEntry, Error>>> = Vec::new();
+    let mut pending = Vec::new();
+    for (i, result) in staged.into_iter().enumerate() {
+        match result {
+            Ok(staged) => {
+                pending.push((i, staged));
+                results.push(None);
+            }
+            Err(e) => results.push(Some(Err(e))),
+        }
+    }
+
+    for batch in pending.chunks(api::FINISH_BATCH_LIMIT) {
+        let entries: Vec<_> = batch.iter().map(|(_, staged)| staged.clone()).collect();
+        match api::finish_batch(cli, access_token, &entries, options) {
+            Ok(committed) => {
+                for ((i, _), result) in batch.iter().zip(committed) {
+                    results[*i] = Some(result);
+                }
+            }
+            Err(e) => {
+                for (i, _) in batch {
+                    results[*i] = Some(Err(CommitError(e.to_string()).into()));
+                }
+            }
+        }
+    }
+
+    // a file which was received broken is uploaded again on its own
+    for (i, result) in results.iter_mut().enumerate() {
+        let mismatched = match result {
+            Some(Err(e)) => api::is_hash_mismatch(e),
+            _ => false,
+        };
+        if mismatched {
+            log::warn!("upload {} again", sources[i].path.display());
+            *result = Some(download_file(
+                cli,
+                access_token,
+                gen,
+                options,
+                &sources[i],
+                &ProgressBar::hidden(),
+            ));
+        }
+    }
+    results
+        .into_iter()
+        .map(|result| result.expect("result of every staged file"))
+        .collect()
+}
+
+pub struct DownloadOptions {
+    pub name: String,
+    pub quiet: bool,
+    pub jobs: usize,
+    pub streams: usize,
+    pub retries: usize,
+    pub resume: bool,
+    pub chunk_size: Option<usize>,
+    pub tar: bool,
+    pub gzip: bool,
+    pub tag: Option<String>,
+}
+
+pub fn run(paths: &[PathBuf], options: &DownloadOptions, walk: &WalkOptions) -> Result<(), Error> {
+    let DownloadOptions {
+        ref name,
+        quiet,
+        jobs,
+        streams,
+        retries,
+        resume,
+        chunk_size,
+        tar,
+        gzip,
+        ref tag,
+    } = *options;
+    let tag = tag.as_ref().map(String::as_str);
+    let (min, max) = (api::MIN_CHUNK_SIZE / MIB, api::MAX_CHUNK_SIZE / MIB);
+    if let Some(size) = chunk_size {
+        if size == 0 || size % min != 0 || size > max {
+            Err(InvalidChunkSize(min, max, size))?;
+        }
+    }
+    if let Some(tag) = tag {
+        route::check_tag(tag)?;
+    }
+    // a tagged file is put under the tag folder, which the server does not make locally
+    let tagged = |name: &str| match tag {
+        Some(tag) => format!("/{}{}{}", route::TAG_PREFIX, tag, name),
+        None => name.to_owned(),
+    };
 
-pub fn run(paths: &[PathBuf], name: &str, quiet: bool) -> Result<(), Error> {
     let cli = ClientBuilder::new()
         .timeout(Duration::from_secs(10 * 60))
         .build()
@@ -76,23 +423,116 @@ pub fn run(paths: &[PathBuf], name: &str, quiet: bool) -> Result<(), Error> {
 
     let config = Config::load()?;
     let cipher_gen = config.cipher_gen()?;
+    let options = api::UploadOptions {
+        chunk_size: chunk_size.map(|s| s * MIB).unwrap_or(api::MIN_CHUNK_SIZE),
+        adaptive: chunk_size.is_none(),
+        streams: streams,
+        retries: retries,
+        retry_wait: RETRY_WAIT,
+        client_modified: None,
+    };
 
-    for path in paths {
-        match download_file(&cli, &config.access_token, &cipher_gen, path, quiet) {
-            Ok(()) => log::info!("{} is uploaded to Dropbox", path.display()),
-            Err(e) => log::error!("{} is not uploaded to Dropbox: {}", path.display(), e),
+    // with `tar`, each directory is uploaded as one archive
+    let (dirs, paths): (Vec<_>, Vec<_>) = paths.iter().cloned().partition(|p| tar && p.is_dir());
+    let mut sources = walk::sources(&paths, walk)?;
+    for source in sources.iter_mut() {
+        source.name = tagged(&source.name);
+    }
+    if resume {
+        for pending in UploadState::pending() {
+            if !sources
+                .iter()
+                .any(|s| fs::canonicalize(&s.path).ok().as_ref() == Some(&pending.path))
+            {
+                sources.push(pending);
+            }
         }
     }
+    let sources = &sources;
+
+    let mut failed = 0;
+    let results = download_files(
+        &cli,
+        &config.access_token,
+        &cipher_gen,
+        &options,
+        sources,
+        quiet,
+        jobs,
+    );
+    for (source, result) in sources.iter().zip(results) {
+        match result {
+            Ok(entry) => log::info!(
+                "{} is uploaded to Dropbox as {}",
+                source.path.display(),
+                entry.path_display
+            ),
+            Err(e) => {
+                failed += 1;
+                log::error!(
+                    "{} is not uploaded to Dropbox: {}",
+                    source.path.display(),
+                    e
+                )
+            }
+        }
+    }
+    for dir in &dirs {
+        let result = archive::tar_name(dir, gzip).and_then(|name| {
+            let name = tagged(&name);
+            let pb = if quiet {
+                ProgressBar::hidden()
+            } else {
+                spinner(dir)
+            };
+            let body = pb.wrap_read(archive::tar(dir, walk, gzip)?);
+            let result = download_read(
+                &cli,
+                &config.access_token,
+                &cipher_gen,
+                &options,
+                &name,
+                body,
+                None,
+                None,
+            );
+            pb.finish_and_clear();
+            result
+        });
+        match result {
+            Ok(entry) => log::info!(
+                "{} is uploaded to Dropbox as {}",
+                dir.display(),
+                entry.path_display
+            ),
+            Err(e) => {
+                failed += 1;
+                log::error!("{} is not uploaded to Dropbox: {}", dir.display(), e)
+            }
+        }
+    }
+
+    let total = sources.len() + dirs.len();
+    if total > 1 {
+        log::info!(
+            "{} file(s) uploaded, {} file(s) failed",
+            total - failed,
+            failed
+        );
+    }
 
     if atty::isnt(atty::Stream::Stdin) {
         match download_read(
             &cli,
             &config.access_token,
             &cipher_gen,
-            &format!("/{}", name),
+            &options,
+            &tagged(&format!("/{}", name)),
             io::stdin(),
+            None,
+            None,
         ) {
-            Ok(()) => log::info!("{} is uploaded to Dropbox", name),
+            Ok(entry) => log::info!("{} is uploaded to Dropbox as {}", name, entry.path_display),
             Err(e) => log::error!("{} is not uploaded to Dropbox: {}", name, e),
         }
     }
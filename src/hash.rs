@@ -0,0 +1,52 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::PathBuf;
+
+use failure::{Error, Fail};
+
+use crate::api;
+use crate::config::{CipherGen, Config};
+use crate::crypto::CipherRead;
+
+#[derive(Fail, Debug)]
+#[fail(display = "crypto file is disabled")]
+struct CryptoDisabled;
+
+#[derive(Fail, Debug)]
+#[fail(display = "{} file(s) cannot be hashed", _0)]
+struct HashError(usize);
+
+fn hash_file(path: &PathBuf, gen: &Option<CipherGen>) -> Result<api::ContentHash, Error> {
+    let mut body = BufReader::new(File::open(path)?);
+    let mut hasher = api::ContentHasher::new();
+    match gen {
+        Some(gen) => io::copy(&mut CipherRead::new(gen.cipher(), body), &mut hasher)?,
+        None => io::copy(&mut body, &mut hasher)?,
+    };
+    Ok(hasher.finish())
+}
+
+/// prints the dropbox content hash of each file like sha256sum.
+/// with `encrypted`, the hash is of the file encrypted as it is uploaded.
+pub fn run(paths: &[PathBuf], encrypted: bool) -> Result<(), Error> {
+    let gen = if encrypted {
+        Some(Config::load()?.cipher_gen()?.ok_or(CryptoDisabled)?)
+    } else {
+        None
+    };
+
+    let mut failed = 0;
+    for path in paths {
+        match hash_file(path, &gen) {
+            Ok(hash) => println!("{}  {}", hash, path.display()),
+            Err(e) => {
+                failed += 1;
+                log::error!("cannot hash {}: {}", path.display(), e);
+            }
+        }
+    }
+    if failed > 0 {
+        Err(HashError(failed))?;
+    }
+    Ok(())
+}
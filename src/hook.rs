@@ -0,0 +1,173 @@
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use failure::{Error, Fail};
+
+use crate::api;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// how long the output is waited for after the hook exits
+const OUTPUT_WAIT: Duration = Duration::from_secs(1);
+
+#[derive(Fail, Debug)]
+#[fail(display = "hook for {} exited with {}", _0, _1)]
+struct HookFailed(String, ExitStatus);
+
+#[derive(Fail, Debug)]
+#[fail(display = "hook for {} timed out after {:?}", _0, _1)]
+struct HookTimeout(String, Duration);
+
+// counts the hooks which are running, up to the limit.
+struct Slots {
+    running: Mutex<usize>,
+    free: Condvar,
+    limit: usize,
+}
+
+impl Slots {
+    fn acquire(&self) {
+        let mut running = self.running.lock().expect("lock hook slots");
+        while *running >= self.limit {
+            running = self.free.wait(running).expect("wait hook slots");
+        }
+        *running += 1;
+    }
+
+    fn release(&self) {
+        *self.running.lock().expect("lock hook slots") -= 1;
+        self.free.notify_one();
+    }
+}
+
+/// a command run on every downloaded file, with its details in environment variables:
+/// `PTFS_LOCAL_PATH`, `PTFS_REMOTE_PATH`, `PTFS_SIZE` and `PTFS_CONTENT_HASH`.
+#[derive(Clone)]
+pub struct Hook {
+    command: String,
+    timeout: Duration,
+    slots: Arc<Slots>,
+}
+
+/// a command which runs `command` by the shell.
+#[cfg(unix)]
+pub fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(not(unix))]
+pub fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+fn read_lines<R: Read + Send + 'static>(r: Option<R>) -> Receiver<String> {
+    let (send, recv) = mpsc::channel();
+    if let Some(r) = r {
+        thread::spawn(move || {
+            for line in BufReader::new(r).split(b'\n').filter_map(|l| l.ok()) {
+                if send
+                    .send(String::from_utf8_lossy(&line).into_owned())
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        });
+    }
+    recv
+}
+
+// a process started in the background by the hook may keep the pipe open,
+// so the output is received only for a while after the hook exits.
+fn drain(lines: &Receiver<String>) -> Vec<String> {
+    let deadline = Instant::now() + OUTPUT_WAIT;
+    let mut drained = Vec::new();
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return drained;
+        }
+        match lines.recv_timeout(deadline - now) {
+            Ok(line) => drained.push(line),
+            Err(_) => return drained,
+        }
+    }
+}
+
+fn wait_timeout(child: &mut Child, timeout: Duration) -> io::Result<Option<ExitStatus>> {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if start.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+impl Hook {
+    /// runs `command` by the shell, at most `jobs` at a time, each for up to `timeout`.
+    pub fn new(command: String, timeout: Duration, jobs: usize) -> Hook {
+        Hook {
+            command: command,
+            timeout: timeout,
+            slots: Arc::new(Slots {
+                running: Mutex::new(0),
+                free: Condvar::new(),
+                limit: jobs.max(1),
+            }),
+        }
+    }
+
+    /// runs the hook for `entry` downloaded to `path`, waiting for a free slot.
+    /// the output of the hook is logged.
+    pub fn run(&self, entry: &api::FileEntry, path: &Path) -> Result<(), Error> {
+        self.slots.acquire();
+        let result = self.spawn(entry, path);
+        self.slots.release();
+        result
+    }
+
+    fn spawn(&self, entry: &api::FileEntry, path: &Path) -> Result<(), Error> {
+        let hash = entry
+            .content_hash
+            .as_ref()
+            .map(|h| h.to_string())
+            .unwrap_or_default();
+        let mut child = shell(&self.command)
+            .env("PTFS_LOCAL_PATH", path)
+            .env("PTFS_REMOTE_PATH", &entry.path_display)
+            .env("PTFS_SIZE", entry.size.to_string())
+            .env("PTFS_CONTENT_HASH", hash)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdout = read_lines(child.stdout.take());
+        let stderr = read_lines(child.stderr.take());
+        let status = wait_timeout(&mut child, self.timeout)?;
+        for line in drain(&stdout) {
+            log::info!("hook {}: {}", &entry.path_display, line);
+        }
+        for line in drain(&stderr) {
+            log::warn!("hook {}: {}", &entry.path_display, line);
+        }
+        match status {
+            Some(status) if status.success() => Ok(()),
+            Some(status) => Err(HookFailed(entry.path_display.clone(), status).into()),
+            None => Err(HookTimeout(entry.path_display.clone(), self.timeout).into()),
+        }
+    }
+}
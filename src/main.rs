@@ -7,12 +7,19 @@ use lazy_static::lazy_static;
 
 mod api;
 mod app;
+mod archive;
 mod config;
 mod crypto;
 mod download;
+mod hash;
+mod hook;
 mod login;
+mod resume;
+mod route;
 mod server;
+mod sink;
 mod url;
+mod walk;
 
 lazy_static! {
     static ref DEFAULT_DST: String = {
@@ -52,6 +59,103 @@ enum Opt {
             default_value = "10"
         )]
         retry_wait: u64,
+        #[structopt(
+            short = "-j",
+            long = "--jobs",
+            help = "number of parallel downloads",
+            default_value = "1"
+        )]
+        jobs: usize,
+        #[structopt(
+            long = "--retries",
+            help = "retry count of each download",
+            default_value = "3"
+        )]
+        retries: usize,
+        #[structopt(long = "--small-first", help = "download smaller files first")]
+        small_first: bool,
+        #[structopt(
+            long = "--extract",
+            help = "extract tar and zip archives into a directory"
+        )]
+        extract: bool,
+        #[structopt(
+            long = "--extract-max-size",
+            help = "largest total size in MiB extracted from an archive",
+            default_value = "4096"
+        )]
+        extract_max_size: u64,
+        #[structopt(
+            long = "--extract-max-entries",
+            help = "largest number of entries extracted from an archive",
+            default_value = "10000"
+        )]
+        extract_max_entries: usize,
+        #[structopt(long = "--keep-archive", help = "keep archives after extracting them")]
+        keep_archive: bool,
+        #[structopt(
+            long = "--rules",
+            name = "RULES",
+            help = "JSON file of rules which choose the download directory of each file"
+        )]
+        rules: Option<PathBuf>,
+        #[structopt(
+            long = "--pipe",
+            name = "PIPE",
+            help = "pipe each downloaded file into the command instead of saving it",
+            raw(conflicts_with_all = r#"&[
+                "DST",
+                "RULES",
+                "on_conflict",
+                "delete_skipped",
+                "extract",
+                "keep_archive",
+                "CMD",
+            ]"#)
+        )]
+        pipe: Option<String>,
+        #[structopt(
+            long = "--on-conflict",
+            help = "what to do when a file of the same name exists",
+            default_value = "rename",
+            raw(possible_values = "sink::CONFLICTS")
+        )]
+        on_conflict: sink::Conflict,
+        #[structopt(
+            long = "--delete-skipped",
+            help = "delete files skipped by --on-conflict from Dropbox"
+        )]
+        delete_skipped: bool,
+        #[structopt(
+            long = "--remove-empty-folders",
+            help = "delete folders emptied by downloads from Dropbox. \
+                    a file uploaded into a folder while it is deleted is lost"
+        )]
+        remove_empty_folders: bool,
+        #[structopt(
+            long = "--on-complete",
+            name = "CMD",
+            help = "command run by the shell on each downloaded file, with PTFS_LOCAL_PATH, \
+                    PTFS_REMOTE_PATH, PTFS_SIZE and PTFS_CONTENT_HASH set"
+        )]
+        on_complete: Option<String>,
+        #[structopt(
+            long = "--hook-timeout",
+            help = "timeout of the command in seconds",
+            default_value = "300"
+        )]
+        hook_timeout: u64,
+        #[structopt(
+            long = "--hook-jobs",
+            help = "number of commands run at the same time",
+            default_value = "1"
+        )]
+        hook_jobs: usize,
+        #[structopt(
+            long = "--keep-on-hook-failure",
+            help = "keep files in Dropbox when the command fails"
+        )]
+        keep_on_hook_failure: bool,
     },
     #[structopt(name = "crypto", about = "enable/disable crypto file")]
     Crypto(CryptoOpt),
@@ -68,6 +172,75 @@ enum Opt {
             default_value = "stdin"
         )]
         name: String,
+        #[structopt(
+            short = "-j",
+            long = "--jobs",
+            help = "number of parallel uploads",
+            default_value = "1"
+        )]
+        jobs: usize,
+        #[structopt(
+            short = "-s",
+            long = "--streams",
+            help = "number of chunks uploaded concurrently per file",
+            default_value = "1"
+        )]
+        streams: usize,
+        #[structopt(
+            long = "--retries",
+            help = "retry count of each request",
+            default_value = "5"
+        )]
+        retries: usize,
+        #[structopt(long = "--resume", help = "resume all interrupted uploads")]
+        resume: bool,
+        #[structopt(
+            long = "--chunk-size",
+            help = "fixed chunk size in MiB [default: adjusted by throughput]"
+        )]
+        chunk_size: Option<usize>,
+        #[structopt(
+            short = "-r",
+            long = "--recursive",
+            help = "upload directories recursively"
+        )]
+        recursive: bool,
+        #[structopt(
+            long = "--include",
+            help = "upload only files matching the glob",
+            raw(number_of_values = "1")
+        )]
+        include: Vec<String>,
+        #[structopt(
+            long = "--exclude",
+            help = "skip files and directories matching the glob",
+            raw(number_of_values = "1")
+        )]
+        exclude: Vec<String>,
+        #[structopt(long = "--follow-symlinks", help = "follow symbolic links")]
+        follow_symlinks: bool,
+        #[structopt(long = "--tar", help = "upload directories as tar archives")]
+        tar: bool,
+        #[structopt(
+            short = "-z",
+            long = "--gzip",
+            help = "compress tar archives by gzip",
+            requires = "tar"
+        )]
+        gzip: bool,
+        #[structopt(long = "--tag", help = "tag files for the rules of the server")]
+        tag: Option<String>,
+    },
+    #[structopt(name = "hash", about = "print dropbox content hash of file(s)")]
+    Hash {
+        #[structopt(name = "FILE", help = "file(s) to hash", required = true)]
+        paths: Vec<PathBuf>,
+        #[structopt(
+            short = "-e",
+            long = "--encrypted",
+            help = "hash file(s) encrypted as uploaded"
+        )]
+        encrypted: bool,
     },
 }
 
@@ -104,14 +277,92 @@ fn main() {
             dst,
             timeout,
             retry_wait,
+            jobs,
+            retries,
+            small_first,
+            extract,
+            extract_max_size,
+            extract_max_entries,
+            keep_archive,
+            rules,
+            pipe,
+            on_conflict,
+            delete_skipped,
+            remove_empty_folders,
+            on_complete,
+            hook_timeout,
+            hook_jobs,
+            keep_on_hook_failure,
         } => server::ServerBuilder::new()
             .dst(dst)
             .timeout(timeout)
             .retry_wait(Duration::from_secs(retry_wait))
+            .jobs(jobs)
+            .retries(retries)
+            .order(if small_first {
+                server::Order::SmallFirst
+            } else {
+                server::Order::Name
+            })
+            .extract(if extract {
+                Some(archive::Limits {
+                    max_size: extract_max_size * 1024 * 1024,
+                    max_entries: extract_max_entries,
+                })
+            } else {
+                None
+            })
+            .keep_archive(keep_archive)
+            .rules(rules)
+            .pipe(pipe)
+            .on_conflict(on_conflict)
+            .delete_skipped(delete_skipped)
+            .remove_empty_folders(remove_empty_folders)
+            .on_complete(on_complete.map(|cmd| {
+                hook::Hook::new(cmd, Duration::from_secs(hook_timeout), hook_jobs)
+            }))
+            .keep_on_hook_failure(keep_on_hook_failure)
             .build()
-            .run(),
-        Opt::Download { paths, quiet, name } => download::run(&paths, &name, quiet),
+            .and_then(server::Server::run),
+        Opt::Download {
+            paths,
+            quiet,
+            name,
+            jobs,
+            streams,
+            retries,
+            resume,
+            chunk_size,
+            recursive,
+            include,
+            exclude,
+            follow_symlinks,
+            tar,
+            gzip,
+            tag,
+        } => download::run(
+            &paths,
+            &download::DownloadOptions {
+                name: name,
+                quiet: quiet,
+                jobs: jobs,
+                streams: streams,
+                retries: retries,
+                resume: resume,
+                chunk_size: chunk_size,
+                tar: tar,
+                gzip: gzip,
+                tag: tag,
+            },
+            &walk::WalkOptions {
+                recursive: recursive,
+                include: include,
+                exclude: exclude,
+                follow_symlinks: follow_symlinks,
+            },
+        ),
         Opt::Crypto(flag) => crypto::run(flag.into()),
+        Opt::Hash { paths, encrypted } => hash::run(&paths, encrypted),
     };
 
     let exitcode = match res {
@@ -0,0 +1,150 @@
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use failure::Error;
+use lazy_static::lazy_static;
+use rustc_hex::ToHex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::api;
+use crate::walk::Source;
+
+lazy_static! {
+    static ref UPLOADS_DIR: PathBuf = {
+        let data_local_dir =
+            dirs::data_local_dir().expect("unexpected: data_local_dir is not None");
+        data_local_dir
+            .join(env!("CARGO_PKG_NAME"))
+            .join("uploads")
+    };
+}
+
+// dropbox keeps an upload session for 7 days. give up a little earlier
+// so that a session does not expire in the middle of the resumed upload.
+const SESSION_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60 - 60 * 60);
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UploadState {
+    source: PathBuf,
+    path: String,
+    size: u64,
+    mtime: u64,
+    started: u64,
+    cursor: Option<api::UploadSessionCursor>,
+}
+
+impl UploadState {
+    fn state_path(source: &Path) -> PathBuf {
+        let mut hasher = Sha256::new();
+        hasher.input(source.to_string_lossy().as_bytes());
+        let key: String = hasher.result().to_vec().to_hex();
+        UPLOADS_DIR.join(format!("{}.json", key))
+    }
+
+    fn read(source: &Path) -> Result<UploadState, Error> {
+        let f = fs::File::open(UploadState::state_path(source))?;
+        Ok(serde_json::from_reader(BufReader::new(f))?)
+    }
+
+    /// state of the upload of `source` to `path`.
+    /// a saved session is continued only if the file is unchanged and the session is still alive.
+    pub fn load(source: &Path, path: &str, meta: &fs::Metadata) -> Result<UploadState, Error> {
+        let source = fs::canonicalize(source)?;
+        let state = UploadState {
+            source: source.clone(),
+            path: path.to_owned(),
+            size: meta.len(),
+            mtime: unix_time(meta.modified()?),
+            started: 0,
+            cursor: None,
+        };
+
+        let saved = match UploadState::read(&source) {
+            Ok(saved) => saved,
+            Err(_) => return Ok(state),
+        };
+        if saved.cursor.is_none() {
+            return Ok(state);
+        }
+        if saved.path != state.path || saved.size != state.size || saved.mtime != state.mtime {
+            log::warn!("{} was changed since the last upload, start over", source.display());
+            saved.remove();
+            return Ok(state);
+        }
+        if unix_time(SystemTime::now()) > saved.started + SESSION_LIFETIME.as_secs() {
+            log::warn!("upload session of {} has expired, start over", source.display());
+            saved.remove();
+            return Ok(state);
+        }
+        Ok(saved)
+    }
+
+    /// sources of all interrupted uploads.
+    pub fn pending() -> Vec<Source> {
+        let entries = match fs::read_dir(&*UPLOADS_DIR) {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+        entries
+            .filter_map(|e| e.ok())
+            .filter_map(|e| fs::File::open(e.path()).ok())
+            .filter_map(|f| serde_json::from_reader::<_, UploadState>(BufReader::new(f)).ok())
+            .map(|state| Source {
+                path: state.source,
+                name: state.path,
+            })
+            .collect()
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.cursor.as_ref().map(|c| c.offset as u64).unwrap_or(0)
+    }
+
+    pub fn reset(&mut self) {
+        self.remove();
+        self.started = 0;
+        self.cursor = None;
+    }
+
+    fn save(&self) -> Result<(), io::Error> {
+        fs::create_dir_all(&*UPLOADS_DIR)?;
+        let file = fs::File::create(UploadState::state_path(&self.source))?;
+        let mut bw = BufWriter::new(file);
+        serde_json::to_writer(&mut bw, self)?;
+        bw.flush()
+    }
+
+    pub fn remove(&self) {
+        let path = UploadState::state_path(&self.source);
+        if path.exists() {
+            if let Err(e) = fs::remove_file(&path) {
+                log::warn!("cannot remove {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+impl api::Checkpoint for UploadState {
+    fn resume(&self) -> Option<api::UploadSessionCursor> {
+        self.cursor.clone()
+    }
+
+    fn save(&mut self, cursor: &api::UploadSessionCursor) {
+        if self.cursor.is_none() {
+            self.started = unix_time(SystemTime::now());
+        }
+        self.cursor = Some(cursor.clone());
+        if let Err(e) = UploadState::save(self) {
+            log::warn!("cannot save upload state of {}: {}", self.source.display(), e);
+        }
+    }
+}
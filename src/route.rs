@@ -0,0 +1,136 @@
+use std::fs;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use failure::{Error, Fail};
+use glob::Pattern;
+use serde::Deserialize;
+
+use crate::api;
+
+/// files uploaded with a tag are put under this prefix in Dropbox, as "/@TAG/...".
+pub const TAG_PREFIX: &str = "@";
+
+#[derive(Fail, Debug)]
+#[fail(
+    display = "invalid tag {:?}. a tag must not be empty or contain '/'",
+    _0
+)]
+pub struct InvalidTag(String);
+
+#[derive(Fail, Debug)]
+#[fail(display = "cannot load rules from {:?}: {}", _0, _1)]
+struct RulesLoadError(PathBuf, Error);
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    glob: Option<String>,
+    ext: Option<String>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    tag: Option<String>,
+    dst: PathBuf,
+}
+
+/// every condition given in a rule has to match an entry.
+#[derive(Debug, Clone)]
+struct Rule {
+    glob: Option<Pattern>,
+    ext: Option<String>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    tag: Option<String>,
+    dst: PathBuf,
+}
+
+impl Rule {
+    fn new(config: RuleConfig) -> Result<Rule, Error> {
+        let glob = match config.glob {
+            Some(glob) => Some(Pattern::new(&glob)?),
+            None => None,
+        };
+        Ok(Rule {
+            glob: glob,
+            ext: config
+                .ext
+                .map(|ext| ext.trim_start_matches('.').to_lowercase()),
+            min_size: config.min_size,
+            max_size: config.max_size,
+            tag: config.tag,
+            dst: expand_home(config.dst),
+        })
+    }
+
+    fn matches(&self, path: &Path, size: u64, tag: Option<&str>) -> bool {
+        self.glob.as_ref().map_or(true, |p| p.matches_path(path))
+            && self.ext.as_ref().map_or(true, |ext| {
+                path.extension()
+                    .and_then(|e| e.to_str())
+                    .map_or(false, |e| e.to_lowercase() == *ext)
+            })
+            && self.min_size.map_or(true, |min| size >= min)
+            && self.max_size.map_or(true, |max| size <= max)
+            && self.tag.as_ref().map_or(true, |t| Some(t.as_str()) == tag)
+    }
+}
+
+fn expand_home(path: PathBuf) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path,
+    }
+}
+
+/// checks a tag given to `ptfs download --tag`.
+pub fn check_tag(tag: &str) -> Result<(), Error> {
+    if tag.is_empty() || tag.contains('/') {
+        Err(InvalidTag(tag.to_owned()))?;
+    }
+    Ok(())
+}
+
+/// the tag of a remote path and the path without it, relative to the app folder.
+pub fn split_tag(path_display: &str) -> (Option<&str>, &str) {
+    let path = path_display.trim_start_matches('/');
+    if path.starts_with(TAG_PREFIX) {
+        if let Some(i) = path.find('/') {
+            return (Some(&path[TAG_PREFIX.len()..i]), &path[i + 1..]);
+        }
+    }
+    (None, path)
+}
+
+/// destination directories of downloaded files, chosen by the first matching rule.
+#[derive(Debug, Clone, Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    /// reads a JSON array of rules such as
+    /// `[{"ext": "pdf", "dst": "~/papers"}, {"glob": "models/*.ckpt", "dst": "/scratch/models"}]`.
+    /// a rule may have `glob`, `ext`, `min_size`, `max_size` and `tag`.
+    pub fn load(path: &Path) -> Result<Rules, Error> {
+        let load = || -> Result<Rules, Error> {
+            let f = fs::File::open(path)?;
+            let configs: Vec<RuleConfig> = serde_json::from_reader(BufReader::new(f))?;
+            let rules = configs
+                .into_iter()
+                .map(Rule::new)
+                .collect::<Result<_, _>>()?;
+            Ok(Rules { rules: rules })
+        };
+        load().map_err(|e| RulesLoadError(path.to_owned(), e).into())
+    }
+
+    /// the directory which `entry` is downloaded into, or `default` if no rule matches.
+    /// globs are matched against the remote path without the tag, such as "dir/file.pdf".
+    pub fn dst<'a>(&'a self, entry: &api::FileEntry, default: &'a Path) -> &'a Path {
+        let (tag, path) = split_tag(&entry.path_display);
+        self.rules
+            .iter()
+            .find(|r| r.matches(Path::new(path), entry.size as u64, tag))
+            .map_or(default, |r| &r.dst)
+    }
+}
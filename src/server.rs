@@ -1,21 +1,44 @@
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io::BufWriter;
-use std::path::PathBuf;
-use std::sync::mpsc;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 use std::time::Duration;
 
-use failure::Error;
+use failure::{Error, Fail};
 use reqwest::{Client, ClientBuilder};
 
 use crate::api;
-use crate::config::Config;
-use crate::crypto::CipherWrite;
+use crate::archive;
+use crate::config::{CipherGen, Config};
+use crate::crypto::{CipherRead, CipherWrite, KeystreamSeek};
+use crate::hook;
+use crate::route;
+use crate::sink::{Conflict, FileSink, PipeSink, Sink};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    Name,
+    SmallFirst,
+}
 
 pub struct ServerBuilder<D = ()> {
     _timeout: u64,
     _dst: D,
     _retry_wait: Duration,
+    _jobs: usize,
+    _retries: usize,
+    _order: Order,
+    _extract: Option<archive::Limits>,
+    _keep_archive: bool,
+    _rules: Option<PathBuf>,
+    _pipe: Option<String>,
+    _on_conflict: Conflict,
+    _delete_skipped: bool,
+    _remove_empty_folders: bool,
+    _on_complete: Option<hook::Hook>,
+    _keep_on_hook_failure: bool,
 }
 
 impl ServerBuilder<()> {
@@ -24,6 +47,18 @@ impl ServerBuilder<()> {
             _timeout: 30,
             _dst: (),
             _retry_wait: Duration::from_secs(10),
+            _jobs: 1,
+            _retries: 3,
+            _order: Order::Name,
+            _extract: None,
+            _keep_archive: false,
+            _rules: None,
+            _pipe: None,
+            _on_conflict: Conflict::Rename,
+            _delete_skipped: false,
+            _remove_empty_folders: false,
+            _on_complete: None,
+            _keep_on_hook_failure: false,
         }
     }
 }
@@ -34,6 +69,18 @@ impl<D> ServerBuilder<D> {
             _timeout: self._timeout,
             _dst: dst,
             _retry_wait: self._retry_wait,
+            _jobs: self._jobs,
+            _retries: self._retries,
+            _order: self._order,
+            _extract: self._extract,
+            _keep_archive: self._keep_archive,
+            _rules: self._rules,
+            _pipe: self._pipe,
+            _on_conflict: self._on_conflict,
+            _delete_skipped: self._delete_skipped,
+            _remove_empty_folders: self._remove_empty_folders,
+            _on_complete: self._on_complete,
+            _keep_on_hook_failure: self._keep_on_hook_failure,
         }
     }
 
@@ -46,32 +93,241 @@ impl<D> ServerBuilder<D> {
         self._retry_wait = retry_wait;
         self
     }
+
+    pub fn jobs(mut self, jobs: usize) -> ServerBuilder<D> {
+        self._jobs = jobs.max(1);
+        self
+    }
+
+    pub fn retries(mut self, retries: usize) -> ServerBuilder<D> {
+        self._retries = retries;
+        self
+    }
+
+    pub fn order(mut self, order: Order) -> ServerBuilder<D> {
+        self._order = order;
+        self
+    }
+
+    /// unpacks downloaded archives within `limits`.
+    pub fn extract(mut self, limits: Option<archive::Limits>) -> ServerBuilder<D> {
+        self._extract = limits;
+        self
+    }
+
+    pub fn keep_archive(mut self, keep_archive: bool) -> ServerBuilder<D> {
+        self._keep_archive = keep_archive;
+        self
+    }
+
+    /// files matching the rules in the JSON file `rules` are downloaded
+    /// into their directories instead of `dst`.
+    pub fn rules(mut self, rules: Option<PathBuf>) -> ServerBuilder<D> {
+        self._rules = rules;
+        self
+    }
+
+    /// pipes each downloaded file into a new process of `command` instead of saving it.
+    /// `dst`, the rules, the conflict policy, extraction and the hook do not apply to it.
+    pub fn pipe(mut self, command: Option<String>) -> ServerBuilder<D> {
+        self._pipe = command;
+        self
+    }
+
+    pub fn on_conflict(mut self, conflict: Conflict) -> ServerBuilder<D> {
+        self._on_conflict = conflict;
+        self
+    }
+
+    /// deletes a file from Dropbox when it is skipped by the conflict policy.
+    pub fn delete_skipped(mut self, delete_skipped: bool) -> ServerBuilder<D> {
+        self._delete_skipped = delete_skipped;
+        self
+    }
+
+    /// deletes the folders of a delivered file from Dropbox once they are empty.
+    /// dropbox deletes a folder with its contents, so a file committed into it
+    /// between the check and the deletion is lost.
+    pub fn remove_empty_folders(mut self, remove: bool) -> ServerBuilder<D> {
+        self._remove_empty_folders = remove;
+        self
+    }
+
+    /// runs `hook` on every downloaded file before it is deleted from Dropbox.
+    pub fn on_complete(mut self, hook: Option<hook::Hook>) -> ServerBuilder<D> {
+        self._on_complete = hook;
+        self
+    }
+
+    /// keeps a file in Dropbox if its hook fails.
+    pub fn keep_on_hook_failure(mut self, keep: bool) -> ServerBuilder<D> {
+        self._keep_on_hook_failure = keep;
+        self
+    }
 }
 
 impl ServerBuilder<PathBuf> {
-    pub fn build(self) -> Server {
-        Server {
+    /// fails if the rules cannot be loaded.
+    pub fn build(self) -> Result<Server, Error> {
+        let sink: Arc<dyn Sink> = if let Some(command) = self._pipe {
+            log::info!("pipe downloaded files into `{}`", command);
+            Arc::new(PipeSink::new(command))
+        } else {
+            log::info!("download directory: {}", self._dst.display());
+            let mut rules = route::Rules::default();
+            if let Some(ref path) = self._rules {
+                rules = route::Rules::load(path)?;
+                log::info!("route files by the rules in {}", path.display());
+            }
+            Arc::new(FileSink::new(self._dst, rules, self._on_conflict))
+        };
+        Ok(Server {
+            sink: sink,
             timeout: self._timeout,
-            dst: self._dst,
             retry_wait: self._retry_wait,
+            jobs: self._jobs,
+            retries: self._retries,
+            order: self._order,
+            extract: self._extract,
+            keep_archive: self._keep_archive,
+            delete_skipped: self._delete_skipped,
+            remove_empty_folders: self._remove_empty_folders,
+            on_complete: self._on_complete,
+            keep_on_hook_failure: self._keep_on_hook_failure,
             cli: ClientBuilder::new()
                 .timeout(Duration::from_secs(self._timeout + 60))
                 .build()
                 .expect("build Client from ClientBuilder"),
             access_token: api::AccessToken::new(),
-        }
+        })
     }
 }
 
 #[derive(Clone)]
 pub struct Server {
     timeout: u64,
-    dst: PathBuf,
     retry_wait: Duration,
+    jobs: usize,
+    retries: usize,
+    order: Order,
+    extract: Option<archive::Limits>,
+    keep_archive: bool,
+    delete_skipped: bool,
+    remove_empty_folders: bool,
+    sink: Arc<dyn Sink>,
+    on_complete: Option<hook::Hook>,
+    keep_on_hook_failure: bool,
     cli: Client,
     access_token: api::AccessToken,
 }
 
+#[derive(Fail, Debug)]
+#[fail(
+    display = "content_hash mismatched in {}. expected: {:?}, actual: {:?}",
+    _0, _1, _2
+)]
+struct ContentHashMismatch(String, Option<api::ContentHash>, api::ContentHash);
+
+#[derive(Fail, Debug)]
+#[fail(
+    display = "size mismatched in {}. expected: {}, actual: {}",
+    _0, _1, _2
+)]
+struct SizeMismatch(String, usize, u64);
+
+#[derive(Default)]
+struct QueueState {
+    pending: Vec<api::FileEntry>,
+    known: HashSet<api::Path>,
+    deleted: HashMap<api::Path, u64>,
+    listing: u64,
+}
+
+/// `known` holds ids which are queued, in progress or kept in Dropbox,
+/// so that re-listing the folder never hands the same file to two workers.
+/// `deleted` holds ids deleted from Dropbox with the listing which was running at the time,
+/// so that a listing taken before the deletion does not queue the file again.
+struct Queue {
+    order: Order,
+    state: Mutex<QueueState>,
+    ready: Condvar,
+}
+
+impl Queue {
+    fn new(order: Order) -> Queue {
+        Queue {
+            order: order,
+            state: Mutex::new(QueueState::default()),
+            ready: Condvar::new(),
+        }
+    }
+
+    /// starts a listing of the folder and returns its number.
+    fn begin_listing(&self) -> u64 {
+        let mut state = self.state.lock().expect("lock queue");
+        state.listing += 1;
+        state.listing
+    }
+
+    /// queues `entry` from the listing `listing` unless it was deleted since the listing began.
+    fn push(&self, entry: api::FileEntry, listing: u64) {
+        let mut state = self.state.lock().expect("lock queue");
+        if state
+            .deleted
+            .get(&entry.id)
+            .map_or(false, |&d| d >= listing)
+        {
+            return;
+        }
+        if state.known.insert(entry.id.clone()) {
+            state.pending.push(entry);
+            self.ready.notify_one();
+        }
+    }
+
+    fn pop(&self) -> api::FileEntry {
+        let mut state = self.state.lock().expect("lock queue");
+        loop {
+            let next = match self.order {
+                Order::Name => state
+                    .pending
+                    .iter()
+                    .enumerate()
+                    .min_by(|(_, a), (_, b)| a.cmp(b))
+                    .map(|(i, _)| i),
+                Order::SmallFirst => state
+                    .pending
+                    .iter()
+                    .enumerate()
+                    .min_by(|(_, a), (_, b)| (a.size, a).cmp(&(b.size, b)))
+                    .map(|(i, _)| i),
+            };
+            if let Some(i) = next {
+                return state.pending.swap_remove(i);
+            }
+            state = self.ready.wait(state).expect("wait queue");
+        }
+    }
+
+    /// ends `listing`. no later listing has a file deleted before it.
+    fn end_listing(&self, listing: u64) {
+        let mut state = self.state.lock().expect("lock queue");
+        state.deleted.retain(|_, d| *d > listing);
+    }
+
+    fn forget(&self, entry: &api::FileEntry) {
+        let mut state = self.state.lock().expect("lock queue");
+        state.known.remove(&entry.id);
+    }
+
+    fn deleted(&self, entry: &api::FileEntry) {
+        let mut state = self.state.lock().expect("lock queue");
+        state.known.remove(&entry.id);
+        let listing = state.listing;
+        state.deleted.insert(entry.id.clone(), listing);
+    }
+}
+
 impl Server {
     fn backoff(&self, err: Error, wait: Duration) {
         log::info!("{}\nwait {:?}", err, wait);
@@ -95,105 +351,279 @@ impl Server {
         }
     }
 
+    /// lists the whole app folder recursively.
     fn list(&self) -> api::ListFolderResponse {
-        loop {
-            match api::list_folder(&self.cli, &self.access_token) {
-                Ok(r) => return r,
+        let mut list_folder = loop {
+            match api::list_folder(&self.cli, &self.access_token, "", true) {
+                Ok(r) => break r,
+                Err(err) => self.backoff(err.into(), self.retry_wait),
+            }
+        };
+        while list_folder.has_more {
+            match api::list_folder_continue(&self.cli, &self.access_token, &list_folder.cursor) {
+                Ok(r) => {
+                    list_folder.entries.extend(r.entries);
+                    list_folder.cursor = r.cursor;
+                    list_folder.has_more = r.has_more;
+                }
                 Err(err) => self.backoff(err.into(), self.retry_wait),
             }
         }
+        list_folder
     }
 
-    fn issue_file(&self, name: &str) -> (fs::File, PathBuf) {
-        let mut i = 0;
-        loop {
-            let path = self.dst.join(if i == 0 {
-                name.to_owned()
-            } else {
-                match name.rfind('.') {
-                    None => format!("{} ({})", name, i),
-                    Some(e) => format!("{} ({}){}", &name[..e], i, &name[e..]),
+    /// deletes the folders of a delivered `entry` from Dropbox, from the deepest one,
+    /// as long as they are empty.
+    /// deleting a folder deletes whatever has been committed into it meanwhile.
+    /// each folder is listed right before it is deleted, which narrows the window
+    /// but cannot close it, so this only runs with `remove_empty_folders`.
+    fn remove_empty_folders(&self, entry: &api::FileEntry) {
+        let mut folder = Path::new(&entry.path_display).parent();
+        while let Some(path) = folder.and_then(|f| f.to_str()) {
+            if path.is_empty() || path == "/" {
+                return;
+            }
+            match api::list_folder(&self.cli, &self.access_token, path, false) {
+                Ok(ref r) if r.entries.is_empty() && !r.has_more => {}
+                Ok(_) => return,
+                Err(e) => {
+                    log::warn!("cannot list {}: {}", path, e);
+                    return;
+                }
+            }
+            match api::delete(&self.cli, &self.access_token, &api::Path(path.to_owned())) {
+                Ok(_) => log::info!("deleted empty folder {} from Dropbox", path),
+                Err(e) => {
+                    log::warn!("cannot delete {} from Dropbox: {}", path, e);
+                    return;
+                }
+            }
+            folder = folder.and_then(|f| f.parent());
+        }
+    }
+
+    /// downloads `entry` into its output, continuing from what an earlier attempt wrote.
+    /// the output is committed once its size and hash are verified.
+    /// a broken output is discarded so that the next attempt starts over.
+    /// returns the local path of the file if it is saved.
+    fn fetch(
+        &self,
+        gen: &Option<CipherGen>,
+        entry: &api::FileEntry,
+    ) -> Result<Option<PathBuf>, Error> {
+        let mut output = self.sink.open(entry)?;
+        let offset = output.offset();
+
+        // dropbox hashed the ciphertext, so the written prefix is encrypted again to be hashed.
+        let mut hasher = api::ContentHasher::new();
+        if offset > 0 {
+            log::info!(
+                "resume downloading {} from {} bytes",
+                &entry.path_display,
+                offset
+            );
+            let mut prefix = output.written()?;
+            match gen {
+                Some(ref gen) => io::copy(&mut CipherRead::new(gen.cipher(), prefix), &mut hasher)?,
+                None => io::copy(&mut prefix, &mut hasher)?,
+            };
+        }
+
+        let hash = match gen {
+            Some(ref gen) => {
+                let mut cipher = gen.cipher();
+                cipher.seek_keystream(offset);
+                self.download(entry, offset, hasher, CipherWrite::new(cipher, &mut output))?
+            }
+            None => self.download(entry, offset, hasher, &mut output)?,
+        };
+
+        let written = output.len()?;
+        if written != entry.size as u64 {
+            output.discard()?;
+            return Err(SizeMismatch(entry.path_display.clone(), entry.size, written).into());
+        }
+        if Some(&hash) != entry.content_hash.as_ref() {
+            output.discard()?;
+            return Err(ContentHashMismatch(
+                entry.path_display.clone(),
+                entry.content_hash.clone(),
+                hash,
+            )
+            .into());
+        }
+        output.commit()
+    }
+
+    fn download<W: Write>(
+        &self,
+        entry: &api::FileEntry,
+        offset: u64,
+        hasher: api::ContentHasher,
+        mut dst: W,
+    ) -> Result<api::ContentHash, Error> {
+        // the whole file was written but not verified last time
+        if offset > 0 && offset == entry.size as u64 {
+            return Ok(hasher.finish());
+        }
+        let hash = api::download(
+            &self.cli,
+            &self.access_token,
+            &entry.id,
+            offset,
+            hasher,
+            &mut dst,
+        )?;
+        dst.flush()?;
+        Ok(hash)
+    }
+
+    /// unpacks a delivered archive, which is removed once it is unpacked
+    /// unless `self.keep_archive`.
+    fn extract(&self, path: &Path, limits: archive::Limits) {
+        match archive::extract(path, limits) {
+            Ok(dir) => {
+                log::info!("{} was extracted to {}", path.display(), dir.display());
+                if self.keep_archive {
+                    return;
+                }
+                if let Err(e) = fs::remove_file(path) {
+                    log::warn!("cannot remove {}: {}", path.display(), e);
+                }
+            }
+            Err(e) => log::error!("cannot extract {}: {}", path.display(), e),
+        }
+    }
+
+    // runs the hook on a delivered file. returns false if it failed.
+    fn hook(&self, entry: &api::FileEntry, path: &Path) -> bool {
+        match self.on_complete {
+            Some(ref hook) => match hook.run(entry, path) {
+                Ok(_) => true,
+                Err(e) => {
+                    log::error!("{}", e);
+                    false
+                }
+            },
+            None => true,
+        }
+    }
+
+    // runs the hook on a saved file and extracts it. returns false if the hook failed.
+    fn saved(&self, entry: &api::FileEntry, dst_path: &Path) -> bool {
+        log::info!(
+            "{} was downloaded to {}",
+            &entry.path_display,
+            dst_path.display()
+        );
+        let hooked = self.hook(entry, dst_path);
+        if let Some(limits) = self.extract {
+            if archive::is_archive(dst_path) {
+                self.extract(dst_path, limits);
+            }
+        }
+        hooked
+    }
+
+    /// deletes a delivered `entry` from Dropbox, retrying up to `self.retries` times.
+    /// returns false if the deletion was given up.
+    fn delete(&self, entry: &api::FileEntry) -> bool {
+        for attempt in 0..=self.retries {
+            match api::delete(&self.cli, &self.access_token, &entry.id) {
+                Ok(_) => {
+                    log::info!("deleted {} from Dropbox", &entry.path_display);
+                    if self.remove_empty_folders {
+                        self.remove_empty_folders(entry);
+                    }
+                    return true;
                 }
-            });
+                Err(e) if attempt < self.retries => self.backoff(e.into(), self.retry_wait),
+                Err(e) => log::error!("{}", e),
+            }
+        }
+        log::error!(
+            "give up deleting {} from Dropbox after {} retries",
+            &entry.path_display,
+            self.retries
+        );
+        false
+    }
 
-            if !path.exists() {
-                match fs::File::create(&path) {
-                    Ok(f) => return (f, path),
-                    Err(_) => log::warn!("cannot create {:?}", path),
+    /// download `entry` into the sink and delete it from Dropbox,
+    /// retrying up to `self.retries` times.
+    /// a file skipped by the conflict policy is kept in Dropbox unless `self.delete_skipped`.
+    /// `entry` is forgotten by `queue` once it is deleted or given up,
+    /// so that it is downloaded again if it is listed again.
+    fn process(&self, gen: &Option<CipherGen>, entry: &api::FileEntry, queue: &Queue) {
+        if let Some(existing) = self.sink.skipped(entry) {
+            log::info!(
+                "skip {} as {} exists",
+                &entry.path_display,
+                existing.display()
+            );
+            if self.delete_skipped && self.delete(entry) {
+                queue.deleted(entry);
+            }
+            return;
+        }
+        for attempt in 0..=self.retries {
+            match self.fetch(gen, entry) {
+                Ok(dst_path) => {
+                    let hooked = dst_path.map_or(true, |p| self.saved(entry, &p));
+                    if !hooked && self.keep_on_hook_failure {
+                        log::warn!("keep {} in Dropbox", &entry.path_display);
+                    } else if self.delete(entry) {
+                        queue.deleted(entry);
+                    }
+                    return;
                 }
+                Err(e) if attempt < self.retries => self.backoff(e, self.retry_wait),
+                Err(e) => log::error!("{}", e),
             }
+        }
+        self.sink.abandon(entry);
+        log::error!(
+            "give up downloading {} after {} retries",
+            &entry.path_display,
+            self.retries
+        );
+        queue.forget(entry);
+    }
 
-            i += 1;
+    fn work(&self, gen: &Option<CipherGen>, queue: &Queue) {
+        loop {
+            let entry = queue.pop();
+            self.process(gen, &entry, queue);
         }
     }
 
     pub fn run(mut self) -> Result<(), Error> {
-        log::info!("download directory: {}", self.dst.display());
-        log::info!("server start");
         let config = Config::load()?;
         let gen = config.cipher_gen()?;
         self.access_token = config.access_token;
+        log::info!("server start");
 
-        let (send, recv) = mpsc::channel();
-        let this = self.clone();
-        thread::spawn(move || {
-            let mut list_folder = this.list();
-            loop {
-                list_folder.entries.sort_unstable();
-                for entry in list_folder.entries.into_iter() {
-                    match entry {
-                        api::Entry::File(file) => {
-                            match send.send(file) {
-                                Ok(_) => {}
-                                Err(e) => log::error!("cannot send to channel: {}", e),
-                            };
-                        }
-                        _ => {}
-                    }
-                }
-                this.longpoll(&list_folder.cursor);
-                list_folder = this.list();
-            }
-        });
+        let queue = Arc::new(Queue::new(self.order));
+        for _ in 0..self.jobs {
+            let this = self.clone();
+            let gen = gen.clone();
+            let queue = queue.clone();
+            thread::spawn(move || this.work(&gen, &queue));
+        }
 
+        let mut listing = queue.begin_listing();
+        let mut list_folder = self.list();
         loop {
-            let entry = recv.recv().expect("unexpected: chanel is closed");
-            let (dst, dst_path) = self.issue_file(&entry.name);
-            let mut bw = BufWriter::new(dst);
-            let result = match gen {
-                Some(ref gen) => api::download(
-                    &self.cli,
-                    &self.access_token,
-                    &entry.id,
-                    &mut CipherWrite::new(gen.cipher(), bw),
-                ),
-                None => api::download(&self.cli, &self.access_token, &entry.id, &mut bw),
-            };
-
-            match result {
-                Ok(hash) => {
-                    if Some(hash) != entry.content_hash {
-                        log::error!(
-                            "content_hash mismatched in {}. expected: {:?}, actual: {:?}",
-                            &entry.path_display,
-                            entry.content_hash,
-                            entry.id
-                        );
-                        fs::remove_file(dst_path)?;
-                        continue;
-                    }
-                    log::info!(
-                        "{} was downloaded to {}",
-                        &entry.path_display,
-                        dst_path.display()
-                    );
-                    match api::delete(&self.cli, &self.access_token, &entry.id) {
-                        Ok(_) => log::info!("deleted {} from Dropbox", &entry.path_display),
-                        Err(e) => self.backoff(e.into(), self.retry_wait),
-                    }
+            for entry in list_folder.entries.into_iter() {
+                match entry {
+                    api::Entry::File(file) => queue.push(file, listing),
+                    _ => {}
                 }
-                Err(e) => self.backoff(e.into(), self.retry_wait),
             }
+            queue.end_listing(listing);
+            self.longpoll(&list_folder.cursor);
+            listing = queue.begin_listing();
+            list_folder = self.list();
         }
     }
 }
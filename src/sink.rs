@@ -0,0 +1,437 @@
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Child, ChildStdin, Stdio};
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use failure::{Error, Fail};
+
+use crate::api;
+use crate::hook;
+use crate::route;
+
+#[derive(Fail, Debug)]
+#[fail(display = "`{}` exited with {} for {}", _0, _1, _2)]
+struct PipeFailed(String, std::process::ExitStatus, String);
+
+/// where the decrypted content of downloaded files goes.
+pub trait Sink: Send + Sync {
+    /// opens the output of `entry`, which continues what an earlier attempt wrote.
+    fn open(&self, entry: &api::FileEntry) -> Result<Box<dyn Output>, Error>;
+
+    /// drops what was written for `entry` when its download is given up.
+    fn abandon(&self, entry: &api::FileEntry);
+
+    /// an existing local file for which `entry` is not downloaded at all.
+    fn skipped(&self, _entry: &api::FileEntry) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// the output of one file. it is written from `offset`.
+pub trait Output: Write {
+    /// the length written by an earlier attempt.
+    fn offset(&self) -> u64;
+
+    /// reads what an earlier attempt wrote, up to `offset`.
+    fn written(&mut self) -> Result<Box<dyn Read>, Error>;
+
+    /// the length written so far.
+    fn len(&mut self) -> Result<u64, Error>;
+
+    /// finishes the verified output. returns the local path of the file if it is saved.
+    fn commit(self: Box<Self>) -> Result<Option<PathBuf>, Error>;
+
+    /// drops the broken output so that the next attempt starts over.
+    fn discard(self: Box<Self>) -> Result<(), Error>;
+}
+
+// a rename is durable only after its directory is synced.
+// a directory cannot be opened as a file on windows.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// claims a file name in `dir` which no other file has,
+/// by creating an empty file there.
+fn issue_file(dir: &Path, name: &str) -> Result<PathBuf, Error> {
+    let mut i = 0;
+    loop {
+        let path = dir.join(if i == 0 {
+            name.to_owned()
+        } else {
+            match name.rfind('.') {
+                None => format!("{} ({})", name, i),
+                Some(e) => format!("{} ({}){}", &name[..e], i, &name[e..]),
+            }
+        });
+
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(_) => return Ok(path),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        i += 1;
+    }
+}
+
+// old versions of overwritten files are moved into this directory next to them.
+const VERSIONS_DIR: &str = ".ptfs-versions";
+
+#[derive(Fail, Debug)]
+#[fail(
+    display = "unknown conflict policy {:?}. use rename, overwrite, skip, version or keep-newest",
+    _0
+)]
+pub struct UnknownConflict(String);
+
+/// names of the conflict policies.
+pub const CONFLICTS: &[&str] = &["rename", "overwrite", "skip", "version", "keep-newest"];
+
+/// what is done when a file of the same name exists locally.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conflict {
+    /// saves the file as "name (i).ext".
+    Rename,
+    /// replaces the existing file.
+    Overwrite,
+    /// does not download the file.
+    Skip,
+    /// moves the existing file into `.ptfs-versions/` with its modified time in the name.
+    Version,
+    /// does not download the file if the existing one was modified after the uploaded source,
+    /// which dropbox keeps as client_modified. otherwise replaces it.
+    KeepNewest,
+}
+
+impl FromStr for Conflict {
+    type Err = UnknownConflict;
+
+    fn from_str(s: &str) -> Result<Conflict, UnknownConflict> {
+        match s {
+            "rename" => Ok(Conflict::Rename),
+            "overwrite" => Ok(Conflict::Overwrite),
+            "skip" => Ok(Conflict::Skip),
+            "version" => Ok(Conflict::Version),
+            "keep-newest" => Ok(Conflict::KeepNewest),
+            _ => Err(UnknownConflict(s.to_owned())),
+        }
+    }
+}
+
+// "name.ext" is "name.20190102T030405Z.ext" in the versions directory.
+fn version_name(name: &str, modified: DateTime<Utc>) -> String {
+    let time = modified.format("%Y%m%dT%H%M%SZ");
+    match name.rfind('.') {
+        None | Some(0) => format!("{}.{}", name, time),
+        Some(e) => format!("{}.{}{}", &name[..e], time, &name[e..]),
+    }
+}
+
+// moves an existing file at `path` into the versions directory.
+fn keep_version(path: &Path) -> Result<(), Error> {
+    let (dir, name) = match (path.parent(), path.file_name().and_then(|n| n.to_str())) {
+        (Some(dir), Some(name)) => (dir, name),
+        _ => return Ok(()),
+    };
+    let modified = fs::metadata(path)?.modified()?;
+    let versions = dir.join(VERSIONS_DIR);
+    fs::create_dir_all(&versions)?;
+    let version = issue_file(&versions, &version_name(name, modified.into()))?;
+    fs::rename(path, &version)?;
+    sync_dir(&versions)?;
+    log::info!("{} was moved to {}", path.display(), version.display());
+    Ok(())
+}
+
+/// saves files under the directories chosen by the rules.
+/// a partially downloaded file is kept hidden between the attempts,
+/// and appears under its name only when it is complete.
+pub struct FileSink {
+    dst: PathBuf,
+    rules: route::Rules,
+    conflict: Conflict,
+}
+
+impl FileSink {
+    pub fn new(dst: PathBuf, rules: route::Rules, conflict: Conflict) -> FileSink {
+        FileSink {
+            dst: dst,
+            rules: rules,
+            conflict: conflict,
+        }
+    }
+
+    // the directory which `entry` is downloaded into.
+    fn base_dir(&self, entry: &api::FileEntry) -> &Path {
+        self.rules.dst(entry, &self.dst)
+    }
+
+    // named by the id so that an interrupted download is continued even after a restart.
+    // it is kept in the same directory as the file so that it is renamed within a file system.
+    fn part_path(&self, entry: &api::FileEntry) -> PathBuf {
+        self.base_dir(entry)
+            .join(format!(".{}.part", entry.id.0.replace(':', "-")))
+    }
+
+    // the folders of `entry` in Dropbox are made under its base directory,
+    // except the tag folder.
+    fn local_dir(&self, entry: &api::FileEntry) -> PathBuf {
+        let mut dir = self.base_dir(entry).to_owned();
+        let (_, path) = route::split_tag(&entry.path_display);
+        if let Some(parent) = Path::new(path).parent() {
+            for component in parent.components() {
+                if let Component::Normal(name) = component {
+                    dir.push(name);
+                }
+            }
+        }
+        dir
+    }
+}
+
+impl Sink for FileSink {
+    fn open(&self, entry: &api::FileEntry) -> Result<Box<dyn Output>, Error> {
+        let part = self.part_path(entry);
+        if let Some(dir) = part.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut offset = fs::metadata(&part).map(|m| m.len()).unwrap_or(0);
+        if offset > entry.size as u64 {
+            offset = 0;
+        }
+
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(&part)?;
+        file.set_len(offset)?;
+        let mut writer = BufWriter::new(file.try_clone()?);
+        writer.seek(SeekFrom::Start(offset))?;
+        Ok(Box::new(FileOutput {
+            part: part,
+            dir: self.local_dir(entry),
+            name: entry.name.clone(),
+            conflict: self.conflict,
+            offset: offset,
+            file: file,
+            writer: writer,
+        }))
+    }
+
+    fn abandon(&self, entry: &api::FileEntry) {
+        let part = self.part_path(entry);
+        if part.exists() {
+            if let Err(e) = fs::remove_file(&part) {
+                log::warn!("cannot remove {}: {}", part.display(), e);
+            }
+        }
+    }
+
+    fn skipped(&self, entry: &api::FileEntry) -> Option<PathBuf> {
+        let path = self.local_dir(entry).join(&entry.name);
+        let meta = fs::metadata(&path).ok()?;
+        let skip = match self.conflict {
+            Conflict::Skip => true,
+            Conflict::KeepNewest => match (meta.modified(), entry.client_modified) {
+                (Ok(local), Some(remote)) => DateTime::<Utc>::from(local) >= remote,
+                _ => false,
+            },
+            _ => false,
+        };
+        if skip {
+            Some(path)
+        } else {
+            None
+        }
+    }
+}
+
+struct FileOutput {
+    part: PathBuf,
+    dir: PathBuf,
+    name: String,
+    conflict: Conflict,
+    offset: u64,
+    file: fs::File,
+    writer: BufWriter<fs::File>,
+}
+
+impl Write for FileOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Output for FileOutput {
+    fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn written(&mut self) -> Result<Box<dyn Read>, Error> {
+        let prefix = BufReader::new(fs::File::open(&self.part)?).take(self.offset);
+        Ok(Box::new(prefix))
+    }
+
+    fn len(&mut self) -> Result<u64, Error> {
+        self.writer.flush()?;
+        Ok(self.file.metadata()?.len())
+    }
+
+    /// syncs the file to the disk and moves it to its name in its folder, durably.
+    /// a file which appeared after `skipped` was checked is never overwritten by `Skip`.
+    fn commit(mut self: Box<Self>) -> Result<Option<PathBuf>, Error> {
+        self.writer.flush()?;
+        self.file.sync_all()?;
+        fs::create_dir_all(&self.dir)?;
+        let dst_path = match self.conflict {
+            Conflict::Rename | Conflict::Skip => issue_file(&self.dir, &self.name)?,
+            Conflict::Overwrite | Conflict::KeepNewest => self.dir.join(&self.name),
+            Conflict::Version => {
+                let path = self.dir.join(&self.name);
+                if path.is_file() {
+                    keep_version(&path)?;
+                }
+                path
+            }
+        };
+        if let Err(e) = fs::rename(&self.part, &dst_path) {
+            if self.conflict == Conflict::Rename || self.conflict == Conflict::Skip {
+                let _ = fs::remove_file(&dst_path);
+            }
+            return Err(e.into());
+        }
+        sync_dir(&self.dir)?;
+        Ok(Some(dst_path))
+    }
+
+    fn discard(self: Box<Self>) -> Result<(), Error> {
+        fs::remove_file(&self.part)?;
+        Ok(())
+    }
+}
+
+/// pipes each file into the standard input of a new process of a command.
+/// the file succeeds only if the command exits successfully.
+/// nothing is kept between the attempts, so every attempt starts over.
+/// the command is run by the shell, and only the shell is killed when a file is broken.
+/// a command such as `exec psql ...` makes the command itself be killed.
+pub struct PipeSink {
+    command: String,
+}
+
+impl PipeSink {
+    pub fn new(command: String) -> PipeSink {
+        PipeSink { command: command }
+    }
+}
+
+impl Sink for PipeSink {
+    fn open(&self, entry: &api::FileEntry) -> Result<Box<dyn Output>, Error> {
+        let mut child = hook::shell(&self.command)
+            .env("PTFS_REMOTE_PATH", &entry.path_display)
+            .env("PTFS_SIZE", entry.size.to_string())
+            .stdin(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().map(BufWriter::new);
+        Ok(Box::new(PipeOutput {
+            command: self.command.clone(),
+            path_display: entry.path_display.clone(),
+            child: child,
+            stdin: stdin,
+            len: 0,
+        }))
+    }
+
+    fn abandon(&self, _entry: &api::FileEntry) {}
+}
+
+struct PipeOutput {
+    command: String,
+    path_display: String,
+    child: Child,
+    stdin: Option<BufWriter<ChildStdin>>,
+    len: u64,
+}
+
+impl PipeOutput {
+    fn stdin(&mut self) -> io::Result<&mut BufWriter<ChildStdin>> {
+        self.stdin
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "stdin is closed"))
+    }
+}
+
+impl Write for PipeOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.stdin()?.write(buf)?;
+        self.len += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdin()?.flush()
+    }
+}
+
+impl Output for PipeOutput {
+    fn offset(&self) -> u64 {
+        0
+    }
+
+    fn written(&mut self) -> Result<Box<dyn Read>, Error> {
+        Ok(Box::new(io::empty()))
+    }
+
+    fn len(&mut self) -> Result<u64, Error> {
+        Ok(self.len)
+    }
+
+    /// closes the standard input and waits for the command.
+    fn commit(mut self: Box<Self>) -> Result<Option<PathBuf>, Error> {
+        self.flush()?;
+        self.stdin.take();
+        let status = self.child.wait()?;
+        if !status.success() {
+            Err(PipeFailed(
+                self.command.clone(),
+                status,
+                self.path_display.clone(),
+            ))?;
+        }
+        log::info!("{} was piped into `{}`", &self.path_display, &self.command);
+        Ok(None)
+    }
+
+    /// the command is killed by `drop` before its input ends,
+    /// so that it does not take a broken file.
+    fn discard(self: Box<Self>) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+// a command which is not committed is killed, also when the download fails halfway.
+impl Drop for PipeOutput {
+    fn drop(&mut self) {
+        if self.stdin.is_some() {
+            let _ = self.child.kill();
+        }
+        let _ = self.child.wait();
+    }
+}
@@ -2,6 +2,8 @@ pub const AUTHORIZE: &'static str = "https://www.dropbox.com/oauth2/authorize";
 pub const OAUTH2_TOKEN: &'static str = "https://api.dropboxapi.com/oauth2/token";
 
 pub const LIST_FOLDER: &'static str = "https://api.dropboxapi.com/2/files/list_folder";
+pub const LIST_FOLDER_CONTINUE: &'static str =
+    "https://api.dropboxapi.com/2/files/list_folder/continue";
 pub const LIST_FOLDER_LONGPOLL: &'static str =
     "https://notify.dropboxapi.com/2/files/list_folder/longpoll";
 
@@ -9,10 +11,15 @@ pub const DOWNLOAD: &'static str = "https://content.dropboxapi.com/2/files/downl
 
 pub const DELETE: &'static str = "https://api.dropboxapi.com/2/files/delete_v2";
 
+pub const UPLOAD: &'static str = "https://content.dropboxapi.com/2/files/upload";
 pub const UPLOAD_SESSION_START: &'static str =
     "https://content.dropboxapi.com/2/files/upload_session/start";
 pub const UPLOAD_SESSION_FINISH: &'static str =
     "https://content.dropboxapi.com/2/files/upload_session/finish";
+pub const UPLOAD_SESSION_FINISH_BATCH: &'static str =
+    "https://api.dropboxapi.com/2/files/upload_session/finish_batch";
+pub const UPLOAD_SESSION_FINISH_BATCH_CHECK: &'static str =
+    "https://api.dropboxapi.com/2/files/upload_session/finish_batch/check";
 pub const UPLOAD_SESSION_APPEND: &'static str =
     "https://content.dropboxapi.com/2/files/upload_session/append_v2";
 
@@ -0,0 +1,159 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use failure::{Error, Fail};
+use glob::Pattern;
+use walkdir::WalkDir;
+
+#[derive(Fail, Debug)]
+#[fail(display = "cannot get file name of {:?}", _0)]
+struct CreateNameError(PathBuf);
+
+#[derive(Fail, Debug)]
+#[fail(display = "{:?} is a directory. use --recursive to upload it", _0)]
+struct IsDirectory(PathBuf);
+
+/// a local file and the Dropbox path it is uploaded to.
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub path: PathBuf,
+    pub name: String,
+}
+
+impl Source {
+    pub fn new(path: PathBuf) -> Result<Source, Error> {
+        let name = format!("/{}", file_name(&path)?);
+        Ok(Source {
+            path: path,
+            name: name,
+        })
+    }
+}
+
+pub struct WalkOptions {
+    pub recursive: bool,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub follow_symlinks: bool,
+}
+
+struct Filter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl Filter {
+    fn new(options: &WalkOptions) -> Result<Filter, Error> {
+        let compile = |globs: &[String]| -> Result<Vec<Pattern>, Error> {
+            globs
+                .iter()
+                .map(|g| Pattern::new(g).map_err(Into::into))
+                .collect()
+        };
+        Ok(Filter {
+            include: compile(&options.include)?,
+            exclude: compile(&options.exclude)?,
+        })
+    }
+
+    fn excluded(&self, rel: &Path) -> bool {
+        self.exclude.iter().any(|p| p.matches_path(rel))
+    }
+
+    fn included(&self, rel: &Path) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| p.matches_path(rel)))
+            && !self.excluded(rel)
+    }
+}
+
+fn file_name(path: &Path) -> Result<String, Error> {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| CreateNameError(path.to_owned()))?;
+    Ok(name.to_owned())
+}
+
+pub fn dir_name(dir: &Path) -> Result<String, Error> {
+    file_name(&fs::canonicalize(dir)?)
+}
+
+// regular files under `dir` with their paths relative to `dir`,
+// which globs are matched against.
+fn files(
+    dir: &Path,
+    filter: &Filter,
+    follow_symlinks: bool,
+) -> Result<Vec<(PathBuf, PathBuf)>, Error> {
+    let walker = WalkDir::new(dir)
+        .follow_links(follow_symlinks)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .filter_entry(|e| match e.path().strip_prefix(dir) {
+            Ok(rel) => e.depth() == 0 || !filter.excluded(rel),
+            Err(_) => false,
+        });
+
+    let mut files = Vec::new();
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                log::warn!("skip {}", e);
+                continue;
+            }
+        };
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let rel = entry.path().strip_prefix(dir)?;
+        if filter.included(rel) {
+            files.push((entry.path().to_owned(), rel.to_owned()));
+        }
+    }
+    Ok(files)
+}
+
+/// regular files under `dir` which pass the filters of `options`, with their relative paths.
+pub fn relative_files(dir: &Path, options: &WalkOptions) -> Result<Vec<(PathBuf, PathBuf)>, Error> {
+    files(dir, &Filter::new(options)?, options.follow_symlinks)
+}
+
+// files under `dir` are uploaded under the folder named after `dir`,
+// so that the server makes the same tree.
+fn walk(dir: &Path, filter: &Filter, follow_symlinks: bool) -> Result<Vec<Source>, Error> {
+    let root = format!("/{}", dir_name(dir)?);
+    let mut sources = Vec::new();
+    for (path, rel) in files(dir, filter, follow_symlinks)? {
+        let mut name = root.clone();
+        for component in rel.iter() {
+            name.push('/');
+            name.push_str(
+                component
+                    .to_str()
+                    .ok_or_else(|| CreateNameError(path.to_owned()))?,
+            );
+        }
+        sources.push(Source {
+            path: path,
+            name: name,
+        });
+    }
+    Ok(sources)
+}
+
+/// files to upload for `paths`. a directory is walked if `options.recursive`.
+pub fn sources(paths: &[PathBuf], options: &WalkOptions) -> Result<Vec<Source>, Error> {
+    let filter = Filter::new(options)?;
+    let mut sources = Vec::new();
+    for path in paths {
+        if !fs::metadata(path)?.is_dir() {
+            sources.push(Source::new(path.to_owned())?);
+        } else if options.recursive {
+            sources.extend(walk(path, &filter, options.follow_symlinks)?);
+        } else {
+            Err(IsDirectory(path.to_owned()))?;
+        }
+    }
+    Ok(sources)
+}